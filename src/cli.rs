@@ -1,30 +1,172 @@
+use super::codec::*;
+use super::discovery::looks_like_osu_installation;
+use super::loudness::*;
+use super::plan::PlanFormat;
+use super::sanitize::FilesystemProfile;
+use super::script::*;
 use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourcePriority {
+    Order,
+    Quality,
+}
+
+impl FromStr for SourcePriority {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "order" => Ok(Self::Order),
+            "quality" => Ok(Self::Quality),
+            _ => Err(format!("Unknown source priority {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionStrategy {
+    Suffix,
+    SetId,
+    Fail,
+}
+
+impl FromStr for CollisionStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "suffix" => Ok(Self::Suffix),
+            "set-id" => Ok(Self::SetId),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!("Unknown collision strategy {:?}", s)),
+        }
+    }
+}
+
+/// How tracks that aren't transcoded get to the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMode {
+    Copy,
+    /// Shares the file with osu!, so it can't be retagged.
+    Hardlink,
+    /// Shares the data with osu! until either side changes it.
+    Reflink,
+}
+
+impl FromStr for CopyMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "copy" => Ok(Self::Copy),
+            "hardlink" => Ok(Self::Hardlink),
+            "reflink" => Ok(Self::Reflink),
+            _ => Err(format!("Unknown copy mode {:?}", s)),
+        }
+    }
+}
 
 #[derive(Debug, Clone, new)]
 pub struct CliArguments {
     pub filename_template: String,
-    pub osu_source: PathBuf,
+    pub osu_source: Option<PathBuf>,
     pub songs_destination: PathBuf,
-    pub unicode_filename: bool,
+    pub filename_scripts: FieldScripts,
     pub remove_missing_songs: bool,
+    /// Treats the files in a destination without a manifest as exported by
+    /// an earlier version.
+    pub adopt_existing: bool,
     pub skip_bitmap: bool,
     pub skip_info: bool,
     pub duplicated: bool,
     pub compress: i8,
+    pub collections: Vec<String>,
+    pub playlists: bool,
+    pub extra_sources: Vec<PathBuf>,
+    pub source_priority: SourcePriority,
+    pub report: Option<PathBuf>,
+    pub dry_run: bool,
+    pub plan_format: PlanFormat,
+    pub collisions: CollisionStrategy,
+    pub filesystem: FilesystemProfile,
+    pub tag_scripts: FieldScripts,
+    pub font_coverage: FontCoverage,
+    pub codec: Option<OutputCodec>,
+    pub quality: Option<EncoderQuality>,
+    pub device_codecs: Option<CodecList>,
+    pub max_bitrate: Option<u32>,
+    pub max_file_size: Option<f64>,
+    pub max_sample_rate: Option<u32>,
+    pub copy_mode: CopyMode,
+    pub loudness: LoudnessMode,
+    pub album_gain: AlbumGrouping,
+}
+
+impl CliArguments {
+    /// The osu! folders given on the command line, which may be none when
+    /// they are left to be found automatically.
+    pub fn osu_sources(&self) -> Vec<PathBuf> {
+        let mut osu_sources: Vec<PathBuf> = self.osu_source.iter().cloned().collect();
+        osu_sources.extend(self.extra_sources.iter().cloned());
+        osu_sources
+    }
+    /// The device limits, when any are given.
+    pub fn transcode_policy(&self) -> Option<TranscodePolicy> {
+        let policy = TranscodePolicy::new(
+            self.device_codecs.as_ref().map(|codecs| codecs.0.clone()),
+            self.max_bitrate,
+            self.max_file_size
+                .map(|megabytes| (megabytes * 1_000_000.0) as u64),
+            self.max_sample_rate,
+        );
+        if policy == TranscodePolicy::new(None, None, None, None) {
+            None
+        } else {
+            Some(policy)
+        }
+    }
+    pub fn script_selection(&self) -> ScriptSelection {
+        ScriptSelection::new(
+            self.filename_scripts.clone(),
+            self.tag_scripts.clone(),
+            self.font_coverage.clone(),
+        )
+    }
 }
 
 pub fn get_arguments_parsed() -> CliArguments {
     let mut ca = CliArguments::new(
         "osu! - %a - %t #%i".to_string(),
+        None,
         PathBuf::from(""),
-        PathBuf::from(""),
-        true,
+        FieldScripts::all(ScriptChoice::Auto),
+        false,
         false,
         false,
         false,
         false,
         -1,
+        vec![],
+        false,
+        vec![],
+        SourcePriority::Quality,
+        None,
+        false,
+        PlanFormat::Table,
+        CollisionStrategy::Suffix,
+        FilesystemProfile::Windows,
+        FieldScripts::all(ScriptChoice::Auto),
+        FontCoverage::all(),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        CopyMode::Copy,
+        LoudnessMode::Off,
+        AlbumGrouping::Folder,
     );
+    let mut paths: Vec<PathBuf> = vec![];
     {
         let mut parser = argparse::ArgumentParser::new();
         parser.set_description("Exports your Osu! songs library as a music folder");
@@ -34,10 +176,32 @@ pub fn get_arguments_parsed() -> CliArguments {
             argparse::StoreTrue,
             "Removes songs from the destination folder that can't be found within Osu!",
         );
-        parser.refer(&mut ca.unicode_filename).add_option(
-            &["-a", "--ascii-filenames"],
-            argparse::StoreFalse,
-            "Use ASCII filenames for naming songs in the filesystem",
+        parser.refer(&mut ca.adopt_existing).add_option(
+            &["--adopt-existing"],
+            argparse::StoreTrue,
+            "Overwrite and take over the files in a destination exported before the exporter kept track of its files",
+        );
+        parser
+            .refer(&mut ca.filename_scripts)
+            .add_option(
+                &["-a", "--ascii-filenames"],
+                argparse::StoreConst(FieldScripts::all(ScriptChoice::Romanised)),
+                "Use ASCII filenames for naming songs in the filesystem",
+            )
+            .add_option(
+                &["--filename-script"],
+                argparse::Store,
+                "Write file name fields in \"unicode\", \"romanised\" or \"auto\" (default), per field like \"romanised,artist=unicode\"",
+            );
+        parser.refer(&mut ca.tag_scripts).add_option(
+            &["--tag-script"],
+            argparse::Store,
+            "Write the title and artist tags in \"unicode\", \"romanised\" or \"auto\" (default), per field like --filename-script",
+        );
+        parser.refer(&mut ca.font_coverage).add_option(
+            &["--fonts"],
+            argparse::Store,
+            "Scripts the player's fonts can show for \"auto\": \"all\" (default) or a list of latin, greek, cyrillic, kana, han, hangul",
         );
         parser.refer(&mut ca.skip_bitmap).add_option(
             &["-b", "--skip-bitmap"],
@@ -52,19 +216,61 @@ pub fn get_arguments_parsed() -> CliArguments {
         parser.refer(&mut ca.compress).add_option(
             &["-l", "--lossy-recompression"],
             argparse::Store,
-            "Recompress as MP3 with VBR [0-9]",
+            "Recompress as MP3 with VBR [0-9], like --codec mp3 --quality vbr:N",
+        );
+        parser.refer(&mut ca.codec).add_option(
+            &["--codec"],
+            argparse::StoreOption,
+            "Transcode to \"mp3\", \"opus\", \"aac\" (M4A), \"vorbis\" (Ogg) or \"flac\", only the tracks past the device limits when any are given",
+        );
+        parser.refer(&mut ca.quality).add_option(
+            &["--quality"],
+            argparse::StoreOption,
+            "Encoder quality for --codec: \"vbr:LEVEL\", \"cbr:KBPS\" or \"abr:KBPS\"",
+        );
+        parser.refer(&mut ca.device_codecs).add_option(
+            &["--device-codecs"],
+            argparse::StoreOption,
+            "Only transcode tracks whose codec isn't in this list, like \"mp3,vorbis,aac\"",
+        );
+        parser.refer(&mut ca.max_bitrate).add_option(
+            &["--max-bitrate"],
+            argparse::StoreOption,
+            "Only transcode tracks over this bitrate, in kbps",
+        );
+        parser.refer(&mut ca.max_file_size).add_option(
+            &["--max-file-size"],
+            argparse::StoreOption,
+            "Only transcode tracks over this file size, in MB",
+        );
+        parser.refer(&mut ca.max_sample_rate).add_option(
+            &["--max-sample-rate"],
+            argparse::StoreOption,
+            "Only transcode tracks over this sample rate, in Hz, resampling them to it",
+        );
+        parser.refer(&mut ca.copy_mode).add_option(
+            &["--copy-mode"],
+            argparse::Store,
+            "How to export tracks that aren't transcoded: \"copy\" (default), \"hardlink\" (never retagged) or \"reflink\", copying when linking fails",
+        );
+        parser.refer(&mut ca.loudness).add_option(
+            &["--loudness"],
+            argparse::Store,
+            "Scan the EBU R128 loudness of each track: \"off\" (default), \"tag\" it as ReplayGain (R128 for Opus), or \"normalize\" the transcoded tracks and tag the others",
+        );
+        parser.refer(&mut ca.album_gain).add_option(
+            &["--album-gain"],
+            argparse::Store,
+            "Which tracks --loudness tag gives one album gain: those in the same \"folder\" (default) or \"collection\"; it's approximated from the loudness of each track, weighted by length",
         );
         parser
-            .refer(&mut ca.osu_source)
-            .add_argument("osu_source", argparse::Store, "Your Osu! folder")
-            .required();
-        parser
-            .refer(&mut ca.songs_destination)
+            .refer(&mut paths)
             .add_argument(
-                "songs_destination",
-                argparse::Store,
-                "Your song library folder (NOT Osu!'s)",
+                "paths",
+                argparse::List,
+                "Your Osu! folder (searched for when left out), then your song library folder (NOT Osu!'s)",
             )
+            .metavar("[OSU_SOURCE] SONGS_DESTINATION")
             .required();
         parser.refer(&mut ca.filename_template).add_option(
             &["-t", "--template"],
@@ -76,7 +282,98 @@ pub fn get_arguments_parsed() -> CliArguments {
             argparse::StoreTrue,
             "Skip track deduplication",
         );
+        parser.refer(&mut ca.collections).add_option(
+            &["-c", "--collection"],
+            argparse::Collect,
+            "Only export beatmaps from this osu! collection (repeatable)",
+        );
+        parser.refer(&mut ca.playlists).add_option(
+            &["-p", "--playlists"],
+            argparse::StoreTrue,
+            "Write an M3U8 playlist for each osu! collection",
+        );
+        parser.refer(&mut ca.extra_sources).add_option(
+            &["-s", "--source"],
+            argparse::Collect,
+            "Another Osu! folder or archive folder to merge in (repeatable)",
+        );
+        parser.refer(&mut ca.source_priority).add_option(
+            &["--source-priority"],
+            argparse::Store,
+            "Which copy wins when sources share a set: \"quality\" or \"order\"",
+        );
+        parser.refer(&mut ca.collisions).add_option(
+            &["--collisions"],
+            argparse::Store,
+            "When tracks would share a file name: \"suffix\" them with (2), (3)..., append their \"set-id\", or \"fail\"",
+        );
+        parser.refer(&mut ca.filesystem).add_option(
+            &["--filesystem"],
+            argparse::Store,
+            "Which file names to allow: \"posix\" or \"windows\" (default), also accepted as \"ntfs\", \"exfat\" or \"fat32\"",
+        );
+        parser.refer(&mut ca.report).add_option(
+            &["--report"],
+            argparse::StoreOption,
+            "Write a JSON report of every skipped item to this file",
+        );
+        parser.refer(&mut ca.dry_run).add_option(
+            &["-n", "--dry-run"],
+            argparse::StoreTrue,
+            "Only print what would be exported, merged and removed, writing nothing",
+        );
+        parser.refer(&mut ca.plan_format).add_option(
+            &["--plan-format"],
+            argparse::Store,
+            "How --dry-run prints its plan: \"table\" or \"json\"",
+        );
         parser.parse_args_or_exit();
     }
+    match paths.len() {
+        1 => ca.songs_destination = paths.remove(0),
+        2 => {
+            ca.songs_destination = paths.remove(1);
+            ca.osu_source = Some(paths.remove(0));
+        }
+        _ => {
+            eprintln!(
+                "Expected [OSU_SOURCE] SONGS_DESTINATION, got {} paths",
+                paths.len()
+            );
+            std::process::exit(2);
+        }
+    }
+    if looks_like_osu_installation(&ca.songs_destination) {
+        eprintln!(
+            "{:?} looks like an osu! folder, which is not exported into; give the destination after it, as in OSU_SOURCE SONGS_DESTINATION",
+            ca.songs_destination
+        );
+        std::process::exit(2);
+    }
+    if ca.codec.is_none() && ca.compress >= 0 && ca.compress <= 9 {
+        ca.codec = Some(OutputCodec::Mp3);
+        ca.quality = ca.quality.or(Some(EncoderQuality::Vbr(ca.compress as f32)));
+    }
+    if ca.codec.is_none() && ca.transcode_policy().is_some() {
+        ca.codec = Some(
+            ca.device_codecs
+                .as_ref()
+                .and_then(|codecs| codecs.0.first().copied())
+                .unwrap_or(OutputCodec::Mp3),
+        );
+    }
+    match ca.codec {
+        Some(codec) => {
+            if let Err(e) = codec.check(ca.quality) {
+                eprintln!("Invalid --quality: {}", e);
+                std::process::exit(2);
+            }
+        }
+        None if ca.quality.is_some() => {
+            eprintln!("--quality needs --codec");
+            std::process::exit(2);
+        }
+        None => {}
+    }
     ca
 }
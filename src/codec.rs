@@ -0,0 +1,408 @@
+use serde::Serialize;
+use std::str::FromStr;
+
+// The formats tracks can be transcoded to, how ffmpeg encodes each, and
+// which tracks a device can take as they are.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCodec {
+    Mp3,
+    Opus,
+    Aac,
+    Vorbis,
+    Flac,
+}
+
+impl FromStr for OutputCodec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "mp3" => Ok(Self::Mp3),
+            "opus" => Ok(Self::Opus),
+            "aac" | "m4a" => Ok(Self::Aac),
+            "vorbis" | "ogg" => Ok(Self::Vorbis),
+            "flac" => Ok(Self::Flac),
+            _ => Err(format!("Unknown codec {:?}", s)),
+        }
+    }
+}
+
+/// How hard the encoder squeezes: a VBR level, or a bitrate in kbps that is
+/// held constant (CBR) or on average (ABR). FLAC reads the level as its
+/// compression level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncoderQuality {
+    Vbr(f32),
+    Cbr(u32),
+    Abr(u32),
+}
+
+impl FromStr for EncoderQuality {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (mode, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected vbr:N, cbr:N or abr:N, got {:?}", s))?;
+        let bitrate = || {
+            value
+                .trim_end_matches('k')
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid bitrate {:?}", value))
+        };
+        match mode {
+            "vbr" => value
+                .parse()
+                .map(Self::Vbr)
+                .map_err(|_| format!("Invalid VBR level {:?}", value)),
+            "cbr" => bitrate().map(Self::Cbr),
+            "abr" => bitrate().map(Self::Abr),
+            _ => Err(format!("Unknown quality mode {:?}", mode)),
+        }
+    }
+}
+
+/// The codecs a device plays, like `mp3,vorbis,aac`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecList(pub Vec<OutputCodec>);
+
+impl FromStr for CodecList {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        s.split(',')
+            .map(|codec| codec.trim().parse())
+            .collect::<Result<Vec<OutputCodec>, String>>()
+            .map(Self)
+    }
+}
+
+impl OutputCodec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::Aac => "aac",
+            Self::Vorbis => "vorbis",
+            Self::Flac => "flac",
+        }
+    }
+
+    /// The extension of the container the codec is written in.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::Aac => "m4a",
+            Self::Vorbis => "ogg",
+            Self::Flac => "flac",
+        }
+    }
+
+    fn default_quality(&self) -> EncoderQuality {
+        match self {
+            Self::Mp3 => EncoderQuality::Vbr(2.0),
+            Self::Opus => EncoderQuality::Vbr(96.0),
+            Self::Aac => EncoderQuality::Cbr(192),
+            Self::Vorbis => EncoderQuality::Vbr(5.0),
+            Self::Flac => EncoderQuality::Vbr(5.0),
+        }
+    }
+
+    /// Checks that the codec has the given quality mode, with a level in its
+    /// range.
+    pub fn check(&self, quality: Option<EncoderQuality>) -> Result<(), String> {
+        let level_range = match self {
+            Self::Mp3 => 0.0..=9.0,
+            // Opus VBR has no levels, only a target bitrate.
+            Self::Opus => 6.0..=510.0,
+            Self::Aac => 0.1..=2.0,
+            Self::Vorbis => -1.0..=10.0,
+            Self::Flac => 0.0..=12.0,
+        };
+        match quality {
+            Some(EncoderQuality::Vbr(level)) if !level_range.contains(&level) => Err(format!(
+                "{} VBR goes from {} to {}",
+                self.extension(),
+                level_range.start(),
+                level_range.end()
+            )),
+            Some(EncoderQuality::Cbr(_)) | Some(EncoderQuality::Abr(_)) if *self == Self::Flac => {
+                Err("flac is lossless and only takes a vbr:N compression level".to_string())
+            }
+            Some(EncoderQuality::Cbr(0)) | Some(EncoderQuality::Abr(0)) => {
+                Err("the bitrate can't be 0".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The ffmpeg arguments that follow `-c:a`.
+    pub fn encoder_arguments(&self, quality: Option<EncoderQuality>) -> Vec<String> {
+        let quality = quality.unwrap_or_else(|| self.default_quality());
+        let kbps = |bitrate: u32| format!("{}k", bitrate);
+        match (self, quality) {
+            (Self::Mp3, EncoderQuality::Vbr(level)) => {
+                vec!["libmp3lame".into(), "-q:a".into(), level.to_string()]
+            }
+            (Self::Mp3, EncoderQuality::Cbr(bitrate)) => {
+                vec!["libmp3lame".into(), "-b:a".into(), kbps(bitrate)]
+            }
+            (Self::Mp3, EncoderQuality::Abr(bitrate)) => vec![
+                "libmp3lame".into(),
+                "-b:a".into(),
+                kbps(bitrate),
+                "-abr".into(),
+                "1".into(),
+            ],
+            (Self::Opus, quality) => {
+                let (vbr, bitrate) = match quality {
+                    EncoderQuality::Vbr(bitrate) => ("on", bitrate.round() as u32),
+                    EncoderQuality::Cbr(bitrate) => ("off", bitrate),
+                    EncoderQuality::Abr(bitrate) => ("constrained", bitrate),
+                };
+                vec![
+                    "libopus".into(),
+                    "-vbr".into(),
+                    vbr.into(),
+                    "-b:a".into(),
+                    kbps(bitrate),
+                ]
+            }
+            (Self::Aac, EncoderQuality::Vbr(level)) => {
+                vec!["aac".into(), "-q:a".into(), level.to_string()]
+            }
+            // ffmpeg's own AAC encoder only targets an average bitrate.
+            (Self::Aac, EncoderQuality::Cbr(bitrate))
+            | (Self::Aac, EncoderQuality::Abr(bitrate)) => {
+                vec!["aac".into(), "-b:a".into(), kbps(bitrate)]
+            }
+            (Self::Vorbis, EncoderQuality::Vbr(level)) => {
+                vec!["libvorbis".into(), "-q:a".into(), level.to_string()]
+            }
+            (Self::Vorbis, EncoderQuality::Cbr(bitrate)) => vec![
+                "libvorbis".into(),
+                "-b:a".into(),
+                kbps(bitrate),
+                "-minrate".into(),
+                kbps(bitrate),
+                "-maxrate".into(),
+                kbps(bitrate),
+            ],
+            (Self::Vorbis, EncoderQuality::Abr(bitrate)) => {
+                vec!["libvorbis".into(), "-b:a".into(), kbps(bitrate)]
+            }
+            (Self::Flac, quality) => {
+                let level = match quality {
+                    EncoderQuality::Vbr(level) => level.round() as u32,
+                    _ => 5,
+                };
+                vec![
+                    "flac".into(),
+                    "-compression_level".into(),
+                    level.to_string(),
+                ]
+            }
+        }
+    }
+}
+
+/// What is known of a source audio file before exporting it.
+#[derive(Debug, Clone, new)]
+pub struct AudioProbe {
+    pub codec: Option<OutputCodec>,
+    /// In kbps.
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub file_size: u64,
+}
+
+/// How one track is exported: copied as it is, or transcoded to `codec`
+/// and resampled to `sample_rate`, and why.
+#[derive(Debug, Clone, PartialEq, Serialize, new)]
+pub struct TranscodeDecision {
+    pub codec: Option<OutputCodec>,
+    pub sample_rate: Option<u32>,
+    pub reason: String,
+}
+
+impl TranscodeDecision {
+    /// The same for every track, as without a policy.
+    pub fn fixed(codec: Option<OutputCodec>) -> Self {
+        match codec {
+            Some(codec) => Self::new(
+                Some(codec),
+                None,
+                format!("everything is transcoded to {}", codec.name()),
+            ),
+            None => Self::new(None, None, "everything is copied".to_string()),
+        }
+    }
+
+    /// The ffmpeg arguments that follow `-c:a`.
+    pub fn ffmpeg_arguments(&self, quality: Option<EncoderQuality>) -> Vec<String> {
+        let codec = match self.codec {
+            Some(codec) => codec,
+            None => return vec!["copy".to_string()],
+        };
+        let mut arguments = codec.encoder_arguments(quality);
+        // libopus only encodes at a few rates, and ffmpeg picks the closest
+        // one by itself.
+        if let Some(sample_rate) = self.sample_rate.filter(|_| codec != OutputCodec::Opus) {
+            arguments.extend(["-ar".to_string(), sample_rate.to_string()]);
+        }
+        arguments
+    }
+}
+
+/// The limits of the device the export is for. Tracks within them are
+/// copied untouched, the others are transcoded.
+#[derive(Debug, Clone, PartialEq, new)]
+pub struct TranscodePolicy {
+    codecs: Option<Vec<OutputCodec>>,
+    /// In kbps.
+    max_bitrate: Option<u32>,
+    /// In bytes.
+    max_file_size: Option<u64>,
+    max_sample_rate: Option<u32>,
+}
+
+impl TranscodePolicy {
+    /// Values the probe couldn't tell aren't held against the track, except
+    /// for the codec.
+    pub fn decide(&self, probe: &AudioProbe, target: OutputCodec) -> TranscodeDecision {
+        let mut violations: Vec<String> = vec![];
+        if let Some(codecs) = &self.codecs {
+            match probe.codec {
+                Some(codec) if codecs.contains(&codec) => {}
+                Some(codec) => violations.push(format!("{} isn't supported", codec.name())),
+                None => violations.push("its codec isn't supported".to_string()),
+            }
+        }
+        if let (Some(max_bitrate), Some(bitrate)) = (self.max_bitrate, probe.bitrate) {
+            if bitrate > max_bitrate {
+                violations.push(format!("{} kbps is over {} kbps", bitrate, max_bitrate));
+            }
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            if probe.file_size > max_file_size {
+                violations.push(format!(
+                    "{:.1} MB is over {:.1} MB",
+                    probe.file_size as f64 / 1_000_000.0,
+                    max_file_size as f64 / 1_000_000.0
+                ));
+            }
+        }
+        let resampled = match (self.max_sample_rate, probe.sample_rate) {
+            (Some(max_sample_rate), Some(sample_rate)) if sample_rate > max_sample_rate => {
+                violations.push(format!("{} Hz is over {} Hz", sample_rate, max_sample_rate));
+                Some(max_sample_rate)
+            }
+            _ => None,
+        };
+        if violations.is_empty() {
+            TranscodeDecision::new(None, None, "within the device limits".to_string())
+        } else {
+            TranscodeDecision::new(Some(target), resampled, violations.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device() -> TranscodePolicy {
+        TranscodePolicy::new(
+            Some(vec![OutputCodec::Mp3, OutputCodec::Vorbis]),
+            Some(192),
+            Some(10_000_000),
+            Some(48000),
+        )
+    }
+
+    fn probe(codec: OutputCodec, bitrate: Option<u32>, sample_rate: u32) -> AudioProbe {
+        AudioProbe::new(Some(codec), bitrate, Some(sample_rate), 4_000_000)
+    }
+
+    #[test]
+    fn copies_tracks_within_the_limits() {
+        let decision =
+            device().decide(&probe(OutputCodec::Mp3, Some(192), 44100), OutputCodec::Mp3);
+        assert_eq!(decision.codec, None);
+        assert_eq!(decision.sample_rate, None);
+        assert_eq!(decision.ffmpeg_arguments(None), ["copy"]);
+    }
+
+    #[test]
+    fn transcodes_unsupported_codecs() {
+        let decision = device().decide(
+            &probe(OutputCodec::Flac, Some(128), 44100),
+            OutputCodec::Mp3,
+        );
+        assert_eq!(decision.codec, Some(OutputCodec::Mp3));
+        assert_eq!(decision.reason, "flac isn't supported");
+
+        let unknown = AudioProbe::new(None, Some(128), Some(44100), 4_000_000);
+        assert_eq!(
+            device().decide(&unknown, OutputCodec::Mp3).codec,
+            Some(OutputCodec::Mp3)
+        );
+    }
+
+    #[test]
+    fn transcodes_only_known_bitrates_over_the_limit() {
+        let decision =
+            device().decide(&probe(OutputCodec::Mp3, Some(320), 44100), OutputCodec::Mp3);
+        assert_eq!(decision.codec, Some(OutputCodec::Mp3));
+        assert_eq!(decision.reason, "320 kbps is over 192 kbps");
+
+        let decision = device().decide(&probe(OutputCodec::Mp3, None, 44100), OutputCodec::Mp3);
+        assert_eq!(decision.codec, None);
+    }
+
+    #[test]
+    fn resamples_to_the_highest_sample_rate() {
+        let decision = device().decide(
+            &probe(OutputCodec::Vorbis, Some(128), 96000),
+            OutputCodec::Vorbis,
+        );
+        assert_eq!(decision.codec, Some(OutputCodec::Vorbis));
+        assert_eq!(decision.sample_rate, Some(48000));
+        assert_eq!(decision.reason, "96000 Hz is over 48000 Hz");
+        assert!(decision
+            .ffmpeg_arguments(None)
+            .ends_with(&["-ar".to_string(), "48000".to_string()]));
+    }
+
+    #[test]
+    fn parses_quality_modes() {
+        assert_eq!("vbr:2".parse(), Ok(EncoderQuality::Vbr(2.0)));
+        assert_eq!("vbr:0.5".parse(), Ok(EncoderQuality::Vbr(0.5)));
+        assert_eq!("cbr:320".parse(), Ok(EncoderQuality::Cbr(320)));
+        assert_eq!("cbr:320k".parse(), Ok(EncoderQuality::Cbr(320)));
+        assert_eq!("abr:160k".parse(), Ok(EncoderQuality::Abr(160)));
+        for invalid in [
+            "320",
+            "vbr:fast",
+            "cbr:",
+            "cbr:-1",
+            "abr:160kbps",
+            "lossless:5",
+        ] {
+            assert!(invalid.parse::<EncoderQuality>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_codec_lists() {
+        assert_eq!(
+            "mp3, ogg,m4a".parse(),
+            Ok(CodecList(vec![
+                OutputCodec::Mp3,
+                OutputCodec::Vorbis,
+                OutputCodec::Aac
+            ]))
+        );
+        assert!("mp3,wma".parse::<CodecList>().is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use super::error::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Readers for the `key = value` settings files osu! keeps next to its data:
+// osu!stable's `osu!.<user>.cfg` and osu!lazer's `storage.ini`.
+
+pub fn read_key_values(path: &Path) -> Result<HashMap<String, String>, ExportError> {
+    let bytes = std::fs::read(path).map_err(ExportError::io(path))?;
+    Ok(String::from_utf8_lossy(&bytes)
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#') && !line.starts_with(';'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect())
+}
+
+/// Resolves a folder written in a settings file, which may be relative to the
+/// osu! folder and may use Windows separators. Outside of Windows, drive
+/// letters are looked up in the Wine prefix the osu! folder is in.
+fn resolve_configured_folder(
+    osu_folder: &Path,
+    settings_path: &Path,
+    configured: &str,
+) -> Option<PathBuf> {
+    if configured.is_empty() {
+        return None;
+    }
+    let configured_path = PathBuf::from(configured);
+    let folder = if configured_path.is_absolute() {
+        Some(configured_path)
+    } else if let Some((drive, rest)) = split_drive_letter(configured) {
+        wine_drive_folder(osu_folder, drive).map(|drive_folder| {
+            drive_folder.join(rest.replace('\\', std::path::MAIN_SEPARATOR_STR))
+        })
+    } else {
+        Some(osu_folder.join(configured.replace('\\', std::path::MAIN_SEPARATOR_STR)))
+    };
+    match folder {
+        Some(folder) if folder.is_dir() => Some(folder),
+        _ => {
+            eprintln!(
+                "WARN: folder {:?} set in {:?} was not found, using the default one",
+                configured, settings_path
+            );
+            None
+        }
+    }
+}
+
+/// Splits `D:\osu!\Songs` into `d` and `osu!\Songs`.
+fn split_drive_letter(configured: &str) -> Option<(char, &str)> {
+    let mut chars = configured.chars();
+    let drive = chars.next().filter(char::is_ascii_alphabetic)?;
+    let rest = chars.as_str().strip_prefix(':')?;
+    Some((
+        drive.to_ascii_lowercase(),
+        rest.trim_start_matches(['\\', '/']),
+    ))
+}
+
+/// Where a Windows drive of the Wine prefix holding the osu! folder is: Wine
+/// links every drive in `dosdevices`, and `C:` is `drive_c`.
+fn wine_drive_folder(osu_folder: &Path, drive: char) -> Option<PathBuf> {
+    let prefix = osu_folder.ancestors().find(|ancestor| {
+        ancestor.join("dosdevices").is_dir() || ancestor.join("drive_c").is_dir()
+    })?;
+    let linked_drive = prefix.join("dosdevices").join(format!("{}:", drive));
+    if linked_drive.is_dir() {
+        Some(linked_drive)
+    } else if drive == 'c' {
+        Some(prefix.join("drive_c"))
+    } else {
+        None
+    }
+}
+
+/// The songs folder set by `BeatmapDirectory` in any `osu!.<user>.cfg` of an
+/// osu!stable folder.
+pub fn stable_beatmap_directory(osu_folder: &Path) -> Option<PathBuf> {
+    let mut user_configs: Vec<PathBuf> = osu_folder
+        .read_dir()
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().to_lowercase())
+                .map(|name| {
+                    name.starts_with("osu!.") && name.ends_with(".cfg") && name != "osu!.cfg"
+                })
+                .unwrap_or(false)
+        })
+        .collect();
+    user_configs.sort();
+    user_configs.iter().find_map(|user_config| {
+        let settings = read_key_values(user_config).ok()?;
+        resolve_configured_folder(osu_folder, user_config, settings.get("BeatmapDirectory")?)
+    })
+}
+
+/// The folder osu!lazer really keeps its data in, which `storage.ini` can move
+/// away from the default one.
+pub fn lazer_storage_folder(osu_folder: &Path) -> PathBuf {
+    let storage_ini = osu_folder.join("storage.ini");
+    read_key_values(&storage_ini)
+        .ok()
+        .and_then(|settings| {
+            resolve_configured_folder(osu_folder, &storage_ini, settings.get("FullPath")?)
+        })
+        .unwrap_or_else(|| osu_folder.to_path_buf())
+}
@@ -0,0 +1,78 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+// Usual places osu! is installed to on Linux, natively, under Wine (plain,
+// Lutris or osu-winello) and in flatpak.
+
+fn subfolders(path: &Path) -> Vec<PathBuf> {
+    let mut folders: Vec<PathBuf> = path
+        .read_dir()
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir())
+                .collect()
+        })
+        .unwrap_or_default();
+    folders.sort();
+    folders
+}
+
+fn wine_prefix_candidates(prefix: &Path) -> Vec<PathBuf> {
+    let drive_c = prefix.join("drive_c");
+    let mut candidates: Vec<PathBuf> = subfolders(&drive_c.join("users"))
+        .into_iter()
+        .map(|user| user.join("AppData").join("Local").join("osu!"))
+        .collect();
+    candidates.push(drive_c.join("osu!"));
+    candidates.push(drive_c.join("Program Files").join("osu!"));
+    candidates
+}
+
+/// Whether the folder holds an osu! installation, which must never be
+/// exported into.
+pub fn looks_like_osu_installation(path: &Path) -> bool {
+    path.join("osu!.db").is_file()
+        || path.join("client.realm").is_file()
+        || path.join("Songs").is_dir()
+}
+
+/// Existing folders that may hold an osu! installation, to be validated by
+/// the beatmap set readers.
+pub fn osu_installation_candidates() -> Vec<PathBuf> {
+    let Some(home) = std::env::var_os("HOME").map(PathBuf::from) else {
+        return vec![];
+    };
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| home.join(".local").join("share"));
+    let mut candidates: Vec<PathBuf> = vec![
+        data_home.join("osu"),
+        home.join(".var")
+            .join("app")
+            .join("sh.ppy.osu")
+            .join("data")
+            .join("osu"),
+        data_home.join("osu-wine").join("OSU"),
+        data_home.join("osu-wine").join("osu!"),
+    ];
+    let mut wine_prefixes: Vec<PathBuf> = vec![];
+    if let Some(wine_prefix) = std::env::var_os("WINEPREFIX") {
+        wine_prefixes.push(PathBuf::from(wine_prefix));
+    }
+    wine_prefixes.push(home.join(".wine"));
+    wine_prefixes.extend(subfolders(&data_home.join("wineprefixes")));
+    wine_prefixes.extend(subfolders(&home.join("Games")));
+    for wine_prefix in wine_prefixes {
+        candidates.extend(wine_prefix_candidates(&wine_prefix));
+    }
+    let mut found: Vec<PathBuf> = vec![];
+    for candidate in candidates {
+        if candidate.is_dir() && !found.contains(&candidate) {
+            found.push(candidate);
+        }
+    }
+    found
+}
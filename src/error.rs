@@ -0,0 +1,192 @@
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        reason: String,
+    },
+    Database {
+        path: PathBuf,
+        reason: String,
+    },
+    FFmpeg {
+        path: PathBuf,
+        reason: String,
+    },
+    Tag {
+        path: PathBuf,
+        reason: String,
+    },
+    Input(String),
+}
+
+impl ExportError {
+    pub fn io(path: &Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+    pub fn not_found(path: &Path) -> Self {
+        Self::Io {
+            path: path.to_path_buf(),
+            source: std::io::Error::from(std::io::ErrorKind::NotFound),
+        }
+    }
+    pub fn parse(path: &Path, reason: impl ToString) -> Self {
+        Self::Parse {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+    pub fn database(path: &Path, reason: impl ToString) -> Self {
+        Self::Database {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+    pub fn ffmpeg(path: &Path, reason: impl ToString) -> Self {
+        Self::FFmpeg {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+    pub fn tag(path: &Path, reason: impl ToString) -> Self {
+        Self::Tag {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        }
+    }
+    /// Points the error to another file, for errors raised before the file
+    /// they concern was known.
+    pub fn at(self, path: &Path) -> Self {
+        let path = path.to_path_buf();
+        match self {
+            Self::Io { source, .. } => Self::Io { path, source },
+            Self::Parse { reason, .. } => Self::Parse { path, reason },
+            Self::Database { reason, .. } => Self::Database { path, reason },
+            Self::FFmpeg { reason, .. } => Self::FFmpeg { path, reason },
+            Self::Tag { reason, .. } => Self::Tag { path, reason },
+            Self::Input(reason) => Self::Input(reason),
+        }
+    }
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Io { .. } => "io",
+            Self::Parse { .. } => "parse",
+            Self::Database { .. } => "database",
+            Self::FFmpeg { .. } => "ffmpeg",
+            Self::Tag { .. } => "tag",
+            Self::Input(_) => "input",
+        }
+    }
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Io { path, .. }
+            | Self::Parse { path, .. }
+            | Self::Database { path, .. }
+            | Self::FFmpeg { path, .. }
+            | Self::Tag { path, .. } => Some(path),
+            Self::Input(_) => None,
+        }
+    }
+    pub fn reason(&self) -> String {
+        match self {
+            Self::Io { source, .. } => source.to_string(),
+            Self::Parse { reason, .. }
+            | Self::Database { reason, .. }
+            | Self::FFmpeg { reason, .. }
+            | Self::Tag { reason, .. }
+            | Self::Input(reason) => reason.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for ExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.path() {
+            Some(path) => write!(f, "{} error on {:?}: {}", self.kind(), path, self.reason()),
+            None => write!(f, "{}", self.reason()),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+#[derive(Debug, Clone, Serialize, new)]
+pub struct SkippedItem {
+    pub kind: &'static str,
+    pub path: Option<PathBuf>,
+    pub reason: String,
+}
+
+impl From<&ExportError> for SkippedItem {
+    fn from(error: &ExportError) -> Self {
+        Self::new(
+            error.kind(),
+            error.path().map(Path::to_path_buf),
+            error.reason(),
+        )
+    }
+}
+
+/// A track that was given another file name, as its own was already taken.
+#[derive(Debug, Clone, Serialize, new)]
+pub struct ResolvedCollision {
+    pub source: PathBuf,
+    pub planned: PathBuf,
+    pub destination: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExportReport {
+    pub exported: usize,
+    pub unchanged: usize,
+    pub skipped: Vec<SkippedItem>,
+    pub collisions: Vec<ResolvedCollision>,
+}
+
+impl ExportReport {
+    pub fn skip(&mut self, error: &ExportError) {
+        eprintln!("WARN: skipping, {}", error);
+        self.skipped.push(SkippedItem::from(error));
+    }
+    pub fn resolve(&mut self, collision: ResolvedCollision) {
+        eprintln!(
+            "NOTE: {:?} was already taken, writing {:?} to {:?} instead",
+            collision.planned, collision.source, collision.destination
+        );
+        self.collisions.push(collision);
+    }
+    pub fn print_summary(&self) {
+        eprintln!(
+            "{} track(s) exported, {} unchanged, {} renamed, {} item(s) skipped",
+            self.exported,
+            self.unchanged,
+            self.collisions.len(),
+            self.skipped.len()
+        );
+        let mut kinds: Vec<&'static str> = self.skipped.iter().map(|item| item.kind).collect();
+        kinds.sort();
+        kinds.dedup();
+        for kind in kinds {
+            eprintln!(
+                "  {}: {}",
+                kind,
+                self.skipped.iter().filter(|item| item.kind == kind).count()
+            );
+        }
+    }
+    pub fn write(&self, path: &Path) -> Result<(), ExportError> {
+        let content =
+            serde_json::to_string_pretty(self).map_err(|e| ExportError::parse(path, e))?;
+        std::fs::write(path, content).map_err(ExportError::io(path))
+    }
+}
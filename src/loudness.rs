@@ -0,0 +1,384 @@
+use super::error::*;
+use super::vorbis;
+use std::path::Path;
+use std::str::FromStr;
+
+// EBU R128 loudness, as measured by ffmpeg, and the ReplayGain tags players
+// read it from to even out the volume between tracks.
+
+/// The loudness ReplayGain 2.0 brings tracks to.
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+/// The loudness the gains in Opus tags are relative to.
+const R128_REFERENCE_LOUDNESS: f64 = -23.0;
+/// How close to full scale normalised tracks may peak, in dBTP.
+const NORMALISED_PEAK_CEILING: f64 = -1.0;
+
+const REPLAYGAIN_FIELDS: &[&str] = &[
+    "REPLAYGAIN_TRACK_GAIN",
+    "REPLAYGAIN_TRACK_PEAK",
+    "REPLAYGAIN_ALBUM_GAIN",
+    "REPLAYGAIN_ALBUM_PEAK",
+];
+/// Opus players only read these, and ignore ReplayGain.
+const R128_FIELDS: &[&str] = &["R128_TRACK_GAIN", "R128_ALBUM_GAIN"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoudnessMode {
+    Off,
+    /// Scans every track and writes ReplayGain tags.
+    Tag,
+    /// Turns the volume of transcoded tracks up or down while encoding them,
+    /// and tags the others.
+    Normalize,
+}
+
+impl FromStr for LoudnessMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "off" => Ok(Self::Off),
+            "tag" => Ok(Self::Tag),
+            "normalize" | "normalise" => Ok(Self::Normalize),
+            _ => Err(format!("Unknown loudness mode {:?}", s)),
+        }
+    }
+}
+
+/// Which tracks share an album gain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlbumGrouping {
+    Folder,
+    /// The first selected collection holding the track, if any.
+    Collection,
+}
+
+impl FromStr for AlbumGrouping {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "folder" => Ok(Self::Folder),
+            "collection" => Ok(Self::Collection),
+            _ => Err(format!("Unknown album grouping {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, new)]
+pub struct Loudness {
+    /// In LUFS.
+    pub integrated: f64,
+    /// In dBTP.
+    pub true_peak: f64,
+    /// In seconds.
+    pub duration: f64,
+}
+
+impl Loudness {
+    /// Reads the summary the `ebur128` filter prints when ffmpeg is done,
+    /// along with the duration of the input.
+    pub fn from_ffmpeg_output(output: &str) -> Option<Self> {
+        let summary = &output[output.rfind("Summary:")?..];
+        let value_of = |label: &str| -> Option<f64> {
+            summary
+                .lines()
+                .map(str::trim)
+                .find_map(|line| line.strip_prefix(label))?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()
+        };
+        let duration = output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Duration: "))
+            .and_then(|duration| duration.split(',').next())
+            .and_then(|duration| {
+                duration
+                    .split(':')
+                    .map(|part| part.trim().parse::<f64>().ok())
+                    .try_fold(0.0, |total, part| Some(total * 60.0 + part?))
+            })
+            .unwrap_or(0.0);
+        Some(Self::new(value_of("I:")?, value_of("Peak:")?, duration))
+    }
+
+    /// An approximation of the loudness of the tracks played one after
+    /// another: the mean energy of their integrated loudness, weighted by
+    /// length. EBU R128 would gate the whole album at once instead, which
+    /// the per-track summaries don't allow.
+    pub fn of_album(tracks: &[Loudness]) -> Option<Self> {
+        if tracks.is_empty() {
+            return None;
+        }
+        let duration: f64 = tracks.iter().map(|track| track.duration).sum();
+        let weight = |track: &Loudness| {
+            if duration > 0.0 {
+                track.duration
+            } else {
+                1.0
+            }
+        };
+        let energy: f64 = tracks
+            .iter()
+            .map(|track| weight(track) * 10f64.powf(track.integrated / 10.0))
+            .sum::<f64>()
+            / tracks.iter().map(weight).sum::<f64>();
+        let true_peak = tracks
+            .iter()
+            .map(|track| track.true_peak)
+            .fold(f64::NEG_INFINITY, f64::max);
+        Some(Self::new(10.0 * energy.log10(), true_peak, duration))
+    }
+
+    /// The ReplayGain, in dB.
+    pub fn gain(&self) -> f64 {
+        REFERENCE_LOUDNESS - self.integrated
+    }
+
+    /// The gain that brings the track to the reference loudness without
+    /// peaking over the ceiling.
+    pub fn normalisation_gain(&self) -> f64 {
+        self.gain().min(NORMALISED_PEAK_CEILING - self.true_peak)
+    }
+
+    /// The true peak as a sample value, with 1.0 for full scale.
+    fn peak_amplitude(&self) -> f64 {
+        10f64.powf(self.true_peak / 20.0)
+    }
+
+    /// The gain relative to -23 LUFS in the Q7.8 fixed point of Opus tags.
+    fn r128_gain(&self) -> i16 {
+        ((R128_REFERENCE_LOUDNESS - self.integrated) * 256.0)
+            .round()
+            .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    }
+}
+
+/// The gain tags of one track.
+#[derive(Debug, Clone, Copy, PartialEq, new)]
+pub struct GainTags {
+    pub track: Loudness,
+    pub album: Option<Loudness>,
+}
+
+impl GainTags {
+    fn replaygain_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            (
+                "REPLAYGAIN_TRACK_GAIN",
+                format!("{:.2} dB", self.track.gain()),
+            ),
+            (
+                "REPLAYGAIN_TRACK_PEAK",
+                format!("{:.6}", self.track.peak_amplitude()),
+            ),
+        ];
+        if let Some(album) = &self.album {
+            fields.push(("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", album.gain())));
+            fields.push((
+                "REPLAYGAIN_ALBUM_PEAK",
+                format!("{:.6}", album.peak_amplitude()),
+            ));
+        }
+        fields
+    }
+
+    fn r128_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("R128_TRACK_GAIN", self.track.r128_gain().to_string())];
+        if let Some(album) = &self.album {
+            fields.push(("R128_ALBUM_GAIN", album.r128_gain().to_string()));
+        }
+        fields
+    }
+
+    /// What the tags say, to tell when they need to be written again.
+    pub fn summary(&self) -> String {
+        self.replaygain_fields()
+            .iter()
+            .map(|(field, value)| format!("{}={}", field, value))
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+
+    /// Replaces the gain tags of the file. Formats without any are left
+    /// alone.
+    pub fn write(&self, path: &Path) -> Result<(), ExportError> {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("mp3") => self.write_id3(path),
+            Some("m4a" | "mp4") => self.write_mp4(path),
+            Some("flac") => self.write_flac(path),
+            Some("ogg") => {
+                vorbis::replace_comments(path, REPLAYGAIN_FIELDS, &self.replaygain_fields())
+            }
+            Some("opus") => vorbis::replace_comments(
+                path,
+                &[REPLAYGAIN_FIELDS, R128_FIELDS].concat(),
+                &self.r128_fields(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_id3(&self, path: &Path) -> Result<(), ExportError> {
+        use id3::TagLike;
+        let mut tag = id3::no_tag_ok(id3::Tag::read_from_path(path))
+            .map_err(|e| ExportError::tag(path, e))?
+            .unwrap_or_default();
+        // Taggers disagree on the case of the descriptions.
+        let earlier: Vec<String> = tag
+            .extended_texts()
+            .map(|text| text.description.clone())
+            .filter(|description| REPLAYGAIN_FIELDS.contains(&description.to_uppercase().as_str()))
+            .collect();
+        for description in earlier.iter() {
+            tag.remove_extended_text(Some(description), None);
+        }
+        for (field, value) in self.replaygain_fields() {
+            tag.add_frame(id3::frame::ExtendedText {
+                description: field.to_string(),
+                value,
+            });
+        }
+        tag.write_to_path(path, id3::Version::Id3v24)
+            .map_err(|e| ExportError::tag(path, e))
+    }
+
+    fn write_mp4(&self, path: &Path) -> Result<(), ExportError> {
+        let mut tag = mp4ameta::Tag::read_from_path(path).map_err(|e| ExportError::tag(path, e))?;
+        let names: Vec<String> = REPLAYGAIN_FIELDS
+            .iter()
+            .map(|field| field.to_lowercase())
+            .collect();
+        for name in names.iter() {
+            tag.remove_data_of(&mp4ameta::FreeformIdent::new("com.apple.iTunes", name));
+        }
+        for (field, value) in self.replaygain_fields() {
+            let name = field.to_lowercase();
+            tag.set_data(
+                mp4ameta::FreeformIdent::new("com.apple.iTunes", &name),
+                mp4ameta::Data::Utf8(value),
+            );
+        }
+        tag.write_to_path(path)
+            .map_err(|e| ExportError::tag(path, e))
+    }
+
+    fn write_flac(&self, path: &Path) -> Result<(), ExportError> {
+        let mut tag = metaflac::Tag::read_from_path(path).map_err(|e| ExportError::tag(path, e))?;
+        for field in REPLAYGAIN_FIELDS.iter() {
+            tag.remove_vorbis(field);
+        }
+        for (field, value) in self.replaygain_fields() {
+            tag.set_vorbis(field, vec![value]);
+        }
+        tag.write_to_path(path)
+            .map_err(|e| ExportError::tag(path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `ffmpeg -i song.mp3 -filter:a ebur128=peak=true -f null -`
+    /// prints, short of the progress lines.
+    const FFMPEG_OUTPUT: &str = "\
+Input #0, mp3, from 'song.mp3':
+  Metadata:
+    title           : Song
+  Duration: 00:03:25.46, start: 0.025057, bitrate: 192 kb/s
+  Stream #0:0: Audio: mp3, 44100 Hz, stereo, fltp, 192 kb/s
+Stream mapping:
+  Stream #0:0 -> #0:0 (mp3 (mp3float) -> pcm_s16le (native))
+Output #0, null, to 'pipe:':
+  Stream #0:0: Audio: pcm_s16le, 48000 Hz, stereo, s16, 1536 kb/s
+[Parsed_ebur128_0 @ 0x5581c0d3e6c0] Summary:
+
+  Integrated loudness:
+    I:          -9.2 LUFS
+    Threshold: -19.4 LUFS
+
+  Loudness range:
+    LRA:         5.1 LU
+    Threshold: -29.4 LUFS
+    LRA low:   -13.4 LUFS
+    LRA high:   -8.3 LUFS
+
+  True peak:
+    Peak:        1.3 dBFS
+";
+
+    #[test]
+    fn reads_the_ffmpeg_summary() {
+        let loudness = Loudness::from_ffmpeg_output(FFMPEG_OUTPUT).unwrap();
+        assert_eq!(loudness.integrated, -9.2);
+        assert_eq!(loudness.true_peak, 1.3);
+        assert!((loudness.duration - 205.46).abs() < 1e-9);
+    }
+
+    #[test]
+    fn needs_a_summary() {
+        let (before_summary, _) = FFMPEG_OUTPUT.split_once("[Parsed_ebur128_0").unwrap();
+        assert_eq!(Loudness::from_ffmpeg_output(before_summary), None);
+        let (without_peak, _) = FFMPEG_OUTPUT.split_once("  True peak:").unwrap();
+        assert_eq!(Loudness::from_ffmpeg_output(without_peak), None);
+        assert_eq!(Loudness::from_ffmpeg_output(""), None);
+    }
+
+    #[test]
+    fn albums_of_equally_loud_tracks_are_as_loud() {
+        let album = Loudness::of_album(&[
+            Loudness::new(-12.0, -0.5, 180.0),
+            Loudness::new(-12.0, 0.8, 30.0),
+            Loudness::new(-12.0, -3.0, 95.5),
+        ])
+        .unwrap();
+        assert!((album.integrated - -12.0).abs() < 1e-9);
+        assert_eq!(album.true_peak, 0.8);
+        assert_eq!(album.duration, 305.5);
+        assert_eq!(Loudness::of_album(&[]), None);
+    }
+
+    #[test]
+    fn weighs_album_tracks_by_length() {
+        let album = Loudness::of_album(&[
+            Loudness::new(-10.0, 0.0, 300.0),
+            Loudness::new(-30.0, 0.0, 0.0),
+        ])
+        .unwrap();
+        assert!((album.integrated - -10.0).abs() < 1e-9);
+        // Without any length, every track weighs the same.
+        let album = Loudness::of_album(&[
+            Loudness::new(-10.0, 0.0, 0.0),
+            Loudness::new(-10.0 - 10.0 * 3f64.log10(), 0.0, 0.0),
+        ])
+        .unwrap();
+        assert!((album.integrated - (-10.0 + 10.0 * (2.0 / 3.0f64).log10())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clamps_r128_gains() {
+        assert_eq!(Loudness::new(-18.0, 0.0, 0.0).r128_gain(), -1280);
+        assert_eq!(Loudness::new(-23.0, 0.0, 0.0).r128_gain(), 0);
+        assert_eq!(Loudness::new(-200.0, 0.0, 0.0).r128_gain(), i16::MAX);
+        assert_eq!(Loudness::new(200.0, 0.0, 0.0).r128_gain(), i16::MIN);
+    }
+
+    #[test]
+    fn summarises_the_replaygain_tags() {
+        let track = Loudness::new(-9.2, 1.3, 205.46);
+        assert_eq!(
+            GainTags::new(track, None).summary(),
+            "REPLAYGAIN_TRACK_GAIN=-8.80 dB;REPLAYGAIN_TRACK_PEAK=1.161449"
+        );
+        let album = Loudness::new(-12.0, 1.3, 400.0);
+        assert_eq!(
+            GainTags::new(track, Some(album)).summary(),
+            "REPLAYGAIN_TRACK_GAIN=-8.80 dB;REPLAYGAIN_TRACK_PEAK=1.161449;\
+             REPLAYGAIN_ALBUM_GAIN=-6.00 dB;REPLAYGAIN_ALBUM_PEAK=1.161449"
+        );
+    }
+}
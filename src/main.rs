@@ -1,3 +1,4 @@
+#![allow(clippy::too_many_arguments)]
 // #[macro_use]
 // extern crate derivative;
 #[macro_use]
@@ -6,291 +7,1472 @@ extern crate derive_new;
 // extern crate derive_more;
 
 mod cli;
+mod codec;
+mod config;
+mod discovery;
+mod error;
+mod loudness;
+mod manifest;
 mod model;
 mod model2;
+mod osudb;
+mod plan;
+mod playlist;
+mod realm;
+mod sanitize;
+mod script;
+mod template;
+mod vorbis;
 
 use self::cli::*;
+use self::codec::*;
+use self::discovery::*;
+use self::error::*;
+use self::loudness::*;
+use self::manifest::*;
 use self::model::*;
-use core::panic;
+use self::osudb::*;
+use self::plan::*;
+use self::playlist::*;
+use self::sanitize::*;
+use self::template::*;
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::TryFrom;
+use std::path::Path;
 use std::path::PathBuf;
-use std::sync;
-use std::sync::Arc;
+use std::str::FromStr;
 
-type FnBeatmapSetReader = dyn Fn(&PathBuf) -> Result<Box<dyn OsuBeatmapSets>, String>;
+type FnBeatmapSetReader = dyn Fn(&PathBuf) -> Result<Box<dyn OsuBeatmapSets>, ExportError>;
 
-fn main() -> Result<(), String> {
-    let cli_args = get_arguments_parsed();
-    if !cli_args.osu_source.is_dir() {
-        return Err(format!("Path {:?} is not a directory", cli_args.osu_source));
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
     }
-    let beatmap_set_readers_fns: Vec<&FnBeatmapSetReader> = vec![
-        &(|x| Osu40BeatmapSetsReader::try_from(x).map(|a| a.boxed())),
-        &(|x| Osu50BeatmapSetsReader::try_from(x).map(|a| a.boxed())),
-    ];
-    let beatmap_set_readers: Vec<Result<Box<dyn OsuBeatmapSets>, String>> = beatmap_set_readers_fns
-        .iter()
-        .map(|f| f(&cli_args.osu_source))
-        .collect();
-    let beatmap_set_readers_success: Vec<&Box<dyn OsuBeatmapSets>> = beatmap_set_readers
-        .iter()
-        .filter_map(|x| x.as_ref().ok())
-        .collect();
-    let beatmap_set_readers_failures: Vec<&String> = beatmap_set_readers
-        .iter()
-        .filter(|x| x.is_err())
-        .map(|x| x.as_ref().map(|_| "").unwrap_err())
-        .collect();
-    if beatmap_set_readers_success.is_empty() {
-        for beatmap_set_readers_failure in beatmap_set_readers_failures {
-            eprintln!("WARN: {}", beatmap_set_readers_failure);
+}
+
+fn run() -> Result<(), ExportError> {
+    let cli_args = get_arguments_parsed();
+    let filename_template = FilenameTemplate::from_str(&cli_args.filename_template)
+        .map_err(|e| ExportError::Input(format!("Invalid template: {}", e)))?;
+    let script_selection = cli_args.script_selection();
+    let mut report = ExportReport::default();
+    let osu_sources = cli_args.osu_sources();
+    let beatmap_set_readers: Vec<Box<dyn OsuBeatmapSets>> = if osu_sources.is_empty() {
+        discover_beatmap_set_readers()?
+    } else {
+        osu_sources
+            .iter()
+            .map(pick_beatmap_set_reader)
+            .collect::<Result<_, ExportError>>()?
+    };
+    let albums_are_collections =
+        cli_args.loudness == LoudnessMode::Tag && cli_args.album_gain == AlbumGrouping::Collection;
+    let collections: Vec<OsuCollection> =
+        if cli_args.playlists || !cli_args.collections.is_empty() || albums_are_collections {
+            gather_collections(&beatmap_set_readers)?
+        } else {
+            vec![]
+        };
+    let collection_hashes: Option<HashSet<String>> = if cli_args.collections.is_empty() {
+        None
+    } else {
+        Some(
+            select_collections(&collections, &cli_args.collections)?
+                .into_iter()
+                .flat_map(|collection| collection.beatmap_hashes)
+                .collect(),
+        )
+    };
+    let playlist_collections: Vec<OsuCollection> = if cli_args.playlists {
+        select_collections(&collections, &cli_args.collections)?
+    } else {
+        vec![]
+    };
+    let album_collections: Vec<OsuCollection> = if albums_are_collections {
+        select_collections(&collections, &cli_args.collections)?
+    } else {
+        vec![]
+    };
+    let mut beatmap_hash_audios: HashMap<String, PathBuf> = HashMap::new();
+    let mut beatmap_infos_per_source: Vec<Vec<OsuBeatmapInfoHolderSimple>> = vec![];
+    for beatmap_set_reader in beatmap_set_readers.iter() {
+        let beatmap_sets_vec: Vec<Box<dyn OsuBeatmapSet>> = match beatmap_set_reader.beatmap_sets()
+        {
+            Ok(beatmap_sets_vec) => beatmap_sets_vec,
+            Err(e) => {
+                report.skip(&e);
+                continue;
+            }
+        };
+        let mut beatmap_info_vec_vec: Vec<Vec<OsuBeatmapInfoHolder>> = vec![];
+        for beatmap_set in beatmap_sets_vec.iter() {
+            let mut beatmap_infos: Vec<OsuBeatmapInfoHolder> = vec![];
+            for beatmap_info_result in beatmap_set.beatmaps() {
+                match beatmap_info_result {
+                    Ok(beatmap_info) => beatmap_infos.push(beatmap_info),
+                    Err(e) => report.skip(&e),
+                }
+            }
+            beatmap_infos.retain(|beatmap_info| match &collection_hashes {
+                None => true,
+                Some(hashes) => beatmap_info
+                    .md5_hash()
+                    .map(|hash| hashes.contains(&hash))
+                    .unwrap_or(false),
+            });
+            beatmap_info_vec_vec.push(beatmap_infos);
         }
+        if !playlist_collections.is_empty() || !album_collections.is_empty() {
+            for beatmap_info in beatmap_info_vec_vec.iter().flatten() {
+                if let Some(hash) = beatmap_info.md5_hash() {
+                    beatmap_hash_audios
+                        .entry(hash)
+                        .or_insert_with(|| beatmap_info.audio.clone());
+                }
+            }
+        }
+        let beatmap_infos: Vec<OsuBeatmapInfoHolderSimple> = beatmap_info_vec_vec
+            .iter()
+            .flat_map(|beatmap_infos: &Vec<OsuBeatmapInfoHolder>| {
+                distinct_audio_infos(beatmap_infos)
+            })
+            .map(|beatmap_info: &OsuBeatmapInfoHolder| {
+                OsuBeatmapInfoHolderSimple::from(((*beatmap_info).clone(), &script_selection))
+            })
+            .collect();
+        beatmap_infos_per_source.push(beatmap_infos);
     }
-    let beatmap_set_reader = beatmap_set_readers_success.first().ok_or_else(|| {
-        format!(
-            "No healthy osu! folder structure identified at {:?}",
-            &cli_args.osu_source
-        )
-    })?;
-    let beatmap_sets_vec: Vec<Box<dyn OsuBeatmapSet>> = beatmap_set_reader.beatmap_sets();
-    let beatmap_info_vec_vec: Vec<Vec<OsuBeatmapInfoHolder>> = beatmap_sets_vec
-        .iter()
-        .map(|beatmap_set| beatmap_set.beatmaps())
-        .collect();
-    let beatmap_info_option_vec: Vec<Option<&OsuBeatmapInfoHolder>> = beatmap_info_vec_vec
-        .iter()
-        .map(|beatmap_infos: &Vec<OsuBeatmapInfoHolder>| beatmap_infos.first())
-        .collect();
-    let beatmap_infos: Vec<OsuBeatmapInfoHolderSimple> = beatmap_info_option_vec
-        .iter()
-        .filter_map(|beatmap_info_opt: &Option<&OsuBeatmapInfoHolder>| beatmap_info_opt.as_ref())
-        .map(|beatmap_info: &&OsuBeatmapInfoHolder| {
-            OsuBeatmapInfoHolderSimple::from(((*beatmap_info).clone(), cli_args.unicode_filename))
-        })
-        .collect();
-    let deduped_beatmap_infos = if cli_args.duplicated {
-        beatmap_infos
+    let (beatmap_infos, merged_audios) =
+        merge_beatmap_sources(beatmap_infos_per_source, cli_args.source_priority);
+    let (deduped_beatmap_infos, deduped_audios) = if cli_args.duplicated {
+        (beatmap_infos, HashMap::new())
     } else {
-        deduplicate_infos(&beatmap_infos)
+        let (deduped_beatmap_infos, deduped_audios, unprobed) = deduplicate_infos(&beatmap_infos);
+        for e in unprobed.iter() {
+            report.skip(e);
+        }
+        (deduped_beatmap_infos, deduped_audios)
     };
-    std::fs::create_dir_all(&cli_args.songs_destination).unwrap();
-    let beatmap_copies: Vec<(PathBuf, OsuBeatmapInfoHolderSimple)> = deduped_beatmap_infos
+    let transcode_decisions = decide_transcoding(&deduped_beatmap_infos, &cli_args);
+    let surviving_audio = |audio: &PathBuf| -> PathBuf {
+        let merged_audio = merged_audios.get(audio).unwrap_or(audio);
+        deduped_audios
+            .get(merged_audio)
+            .unwrap_or(merged_audio)
+            .clone()
+    };
+    let mut beatmap_copies: Vec<(PathBuf, OsuBeatmapInfoHolderSimple)> = deduped_beatmap_infos
         .into_iter()
         .map(|x| {
-            (
-                x.build_path(&cli_args.songs_destination, &cli_args.filename_template),
-                x,
-            )
+            let audio_extension = match transcode_decisions[&x.audio].codec {
+                Some(codec) => Some(codec.extension()),
+                None => x.extensions.0.as_deref(),
+            };
+            let destination_path = x.build_path(
+                &cli_args.songs_destination,
+                &filename_template,
+                audio_extension,
+                cli_args.filesystem,
+            );
+            (destination_path, x)
         })
         .collect();
-    if cli_args.remove_missing_songs {
-        let beatmap_files: Vec<PathBuf> = beatmap_copies
+    let filesystem = cli_args.filesystem;
+    let owned_files: Option<HashSet<PathBuf>> =
+        match ExportManifest::open_existing(&cli_args.songs_destination)? {
+            Some(manifest) => Some(
+                manifest
+                    .exported_files()?
+                    .iter()
+                    .map(|path| filesystem.collision_key(path))
+                    .collect(),
+            ),
+            None => None,
+        };
+    // Files already there that an earlier export didn't write are someone
+    // else's. Without a manifest to tell, they all are, unless they were
+    // adopted.
+    let is_foreign = |path: &PathBuf| -> bool {
+        path.exists()
+            && match &owned_files {
+                Some(owned_files) => !owned_files.contains(&filesystem.collision_key(path)),
+                None => !cli_args.adopt_existing,
+            }
+    };
+    for collision in make_destinations_unique(
+        &mut beatmap_copies,
+        cli_args.collisions,
+        filesystem,
+        &is_foreign,
+    )? {
+        report.resolve(collision);
+    }
+    let mut playlists: Vec<M3u8Playlist> = {
+        let copies_by_audio: HashMap<&PathBuf, &(PathBuf, OsuBeatmapInfoHolderSimple)> =
+            beatmap_copies
+                .iter()
+                .map(|copy| (&copy.1.audio, copy))
+                .collect();
+        playlist_collections
             .iter()
-            .map(|(path, _)| path.clone())
-            .collect();
-        let existing_files: Vec<PathBuf> = cli_args
-            .songs_destination
-            .read_dir()
-            .unwrap()
-            .filter_map(|x| x.ok())
-            .map(|entry| entry.path())
-            .collect();
-        let files_to_remove: Vec<&PathBuf> = existing_files
+            .map(|collection| {
+                let mut playlist = M3u8Playlist::for_collection(
+                    &cli_args.songs_destination,
+                    &collection.name,
+                    filesystem,
+                );
+                for hash in collection.beatmap_hashes.iter() {
+                    let copy = beatmap_hash_audios
+                        .get(hash)
+                        .map(surviving_audio)
+                        .and_then(|audio| copies_by_audio.get(&audio));
+                    if let Some((destination_path, beatmap_info)) = copy {
+                        playlist.push(destination_path, beatmap_info);
+                    }
+                }
+                playlist
+            })
+            .collect()
+    };
+    for collision in
+        make_playlist_paths_unique(&mut playlists, cli_args.collisions, filesystem, &is_foreign)?
+    {
+        report.resolve(collision);
+    }
+    // The album each track's album gain is measured over, by destination.
+    let album_names: HashMap<PathBuf, String> = match (cli_args.loudness, cli_args.album_gain) {
+        (LoudnessMode::Tag, AlbumGrouping::Folder) => beatmap_copies
             .iter()
-            .filter(|path| !beatmap_files.contains(path))
-            .collect();
-        for file in files_to_remove {
-            std::fs::remove_file(file).unwrap();
+            .filter_map(|(destination_path, _)| {
+                let folder = destination_path.parent()?;
+                Some((
+                    destination_path.clone(),
+                    folder.to_string_lossy().into_owned(),
+                ))
+            })
+            .collect(),
+        (LoudnessMode::Tag, AlbumGrouping::Collection) => {
+            let destinations_by_audio: HashMap<&PathBuf, &PathBuf> = beatmap_copies
+                .iter()
+                .map(|(destination_path, beatmap_info)| (&beatmap_info.audio, destination_path))
+                .collect();
+            let mut album_names: HashMap<PathBuf, String> = HashMap::new();
+            for collection in album_collections.iter() {
+                for hash in collection.beatmap_hashes.iter() {
+                    let destination_path = beatmap_hash_audios
+                        .get(hash)
+                        .map(surviving_audio)
+                        .and_then(|audio| destinations_by_audio.get(&audio));
+                    if let Some(destination_path) = destination_path {
+                        album_names
+                            .entry((*destination_path).clone())
+                            .or_insert_with(|| collection.name.clone());
+                    }
+                }
+            }
+            album_names
         }
+        _ => HashMap::new(),
+    };
+    let planned_files: Vec<PathBuf> = beatmap_copies
+        .iter()
+        .map(|(path, _)| path.clone())
+        .chain(playlists.iter().map(|playlist| playlist.path.clone()))
+        .collect();
+    let planned_files: HashMap<PathBuf, &PathBuf> = planned_files
+        .iter()
+        .map(|path| (filesystem.collision_key(path), path))
+        .collect();
+    if cli_args.dry_run {
+        let plan = plan_export(
+            &cli_args,
+            &beatmap_copies,
+            &transcode_decisions,
+            &playlists,
+            &planned_files,
+            &merged_audios,
+            &deduped_audios,
+            &report,
+        )?;
+        return plan.print(cli_args.plan_format, &cli_args.songs_destination);
     }
-
+    std::fs::create_dir_all(&cli_args.songs_destination)
+        .map_err(ExportError::io(&cli_args.songs_destination))?;
+    let manifest = ExportManifest::open(&cli_args.songs_destination)?;
     let thread_pool = threadpool::ThreadPool::new(
         std::thread::available_parallelism()
-            .and_then(|x| Ok(x.get()))
+            .map(|x| x.get())
             .unwrap_or(2)
             * 2,
     );
+    let (tx, rx) = std::sync::mpsc::channel::<(
+        PathBuf,
+        ExportFingerprint,
+        Result<Option<Loudness>, ExportError>,
+    )>();
+    let mut settled_files: Vec<PathBuf> = vec![];
     for (destination_path, beatmap_info_holder) in beatmap_copies.into_iter() {
+        let decision = transcode_decisions[&beatmap_info_holder.audio].clone();
+        let fingerprint = ExportFingerprint::of(&beatmap_info_holder, &decision, &cli_args);
+        if manifest.is_up_to_date(&destination_path, &fingerprint) {
+            report.unchanged += 1;
+            settled_files.push(destination_path);
+            continue;
+        }
+        if let Some(destination_folder) = destination_path.parent() {
+            if let Err(e) = manifest.create_folders(destination_folder) {
+                report.skip(&e);
+                continue;
+            }
+        }
         let cli_args_cloned = cli_args.clone();
         let beatmap_info_holder_cloned = beatmap_info_holder.clone();
+        let txc = tx.clone();
         thread_pool.execute(move || {
-            do_copy(
-                destination_path,
+            let copy_result = do_copy(
+                destination_path.clone(),
                 beatmap_info_holder_cloned,
+                &decision,
                 cli_args_cloned,
-            )
+            );
+            txc.send((destination_path, fingerprint, copy_result))
+                .unwrap_or(())
+        });
+    }
+    drop(tx);
+    for (destination_path, fingerprint, copy_result) in rx {
+        let record_result = copy_result.and_then(|loudness| {
+            manifest.record(&destination_path, &fingerprint, loudness.as_ref())
         });
+        match record_result {
+            Ok(()) => {
+                report.exported += 1;
+                settled_files.push(destination_path);
+            }
+            Err(e) => report.skip(&e),
+        }
     }
     thread_pool.join();
+    if cli_args.loudness != LoudnessMode::Off {
+        write_gain_tags(&manifest, &settled_files, &album_names, &mut report);
+    }
+    for playlist in playlists.iter() {
+        let write_result = playlist
+            .write()
+            .and_then(|_| manifest.record(&playlist.path, &ExportFingerprint::playlist(), None));
+        if let Err(e) = write_result {
+            report.skip(&e);
+        }
+    }
+    if cli_args.remove_missing_songs {
+        remove_stale_exports(&manifest, &planned_files, filesystem, &mut report)?;
+    }
+    report.print_summary();
+    if let Some(report_path) = &cli_args.report {
+        report.write(report_path)?;
+    }
     Ok(())
 }
 
-fn do_copy(
-    mut destination_path: PathBuf,
-    beatmap_info_holder: OsuBeatmapInfoHolderSimple,
-    cli_args: cli::CliArguments,
+/// Tags every scanned track with its gain and that of its album, once the
+/// loudness of all of them is known. Tags that are already right are left
+/// alone, so only the albums that changed are written again.
+fn write_gain_tags(
+    manifest: &ExportManifest,
+    files: &[PathBuf],
+    album_names: &HashMap<PathBuf, String>,
+    report: &mut ExportReport,
 ) {
-    let compressing = cli_args.compress >= 0 && cli_args.compress <= 9;
-    if compressing {
-        destination_path.set_extension("mp3");
+    let scanned: Vec<(&PathBuf, Loudness, String)> = files
+        .iter()
+        .filter_map(|file| {
+            let (loudness, written) = manifest.loudness(file)?;
+            Some((file, loudness, written))
+        })
+        .collect();
+    let mut album_tracks: HashMap<&String, Vec<Loudness>> = HashMap::new();
+    for (file, loudness, _) in scanned.iter() {
+        if let Some(album_name) = album_names.get(*file) {
+            album_tracks.entry(album_name).or_default().push(*loudness);
+        }
+    }
+    let album_loudnesses: HashMap<&String, Loudness> = album_tracks
+        .iter()
+        .filter_map(|(album_name, tracks)| Some((*album_name, Loudness::of_album(tracks)?)))
+        .collect();
+    for (file, loudness, written) in scanned.into_iter() {
+        let album_loudness = album_names
+            .get(file)
+            .and_then(|album_name| album_loudnesses.get(album_name))
+            .copied();
+        let gain_tags = GainTags::new(loudness, album_loudness);
+        let summary = gain_tags.summary();
+        if summary == written {
+            continue;
+        }
+        let write_result = gain_tags
+            .write(file)
+            .and_then(|_| manifest.record_gain_tags(file, &summary));
+        if let Err(e) = write_result {
+            report.skip(&e);
+        }
     }
-    if !destination_path.is_file() || cli_args.remove_missing_songs {
+}
+
+/// Works out what an export would do, without writing anything.
+fn plan_export(
+    cli_args: &CliArguments,
+    beatmap_copies: &[(PathBuf, OsuBeatmapInfoHolderSimple)],
+    transcode_decisions: &HashMap<PathBuf, TranscodeDecision>,
+    playlists: &[M3u8Playlist],
+    planned_files: &HashMap<PathBuf, &PathBuf>,
+    merged_audios: &HashMap<PathBuf, PathBuf>,
+    deduped_audios: &HashMap<PathBuf, PathBuf>,
+    report: &ExportReport,
+) -> Result<ExportPlan, ExportError> {
+    let manifest = ExportManifest::open_existing(&cli_args.songs_destination)?;
+    let mut plan = ExportPlan::default();
+    // Hardlinks can't cross filesystems, and copies made instead are tagged.
+    let existing_destination = std::path::absolute(&cli_args.songs_destination)
+        .ok()
+        .and_then(|destination| {
+            destination
+                .ancestors()
+                .find(|ancestor| ancestor.exists())
+                .map(Path::to_path_buf)
+        });
+    let can_hardlink = |source: &Path| {
+        archive_entry(source).is_none()
+            && existing_destination
+                .as_ref()
+                .map(|destination| is_same_filesystem(source, destination))
+                .unwrap_or(false)
+    };
+    for (destination_path, beatmap_info) in beatmap_copies.iter() {
+        let decision = &transcode_decisions[&beatmap_info.audio];
+        let fingerprint = ExportFingerprint::of(beatmap_info, decision, cli_args);
+        let action = if manifest
+            .as_ref()
+            .map(|manifest| manifest.is_up_to_date(destination_path, &fingerprint))
+            .unwrap_or(false)
         {
-            let sps = subprocess::Exec::cmd("ffmpeg")
-                .arg("-y")
-                .arg("-i")
-                .arg(beatmap_info_holder.audio.to_str().unwrap())
-                .arg("-map")
-                .arg("0:a")
-                .arg("-c:a");
-            match compressing {
-                false => sps.arg("copy"),
-                true => sps
-                    .arg("libmp3lame")
-                    .arg("-q:a")
-                    .arg(std::ffi::OsStr::new(cli_args.compress.to_string().as_str())),
+            TrackAction::Unchanged
+        } else if decision.codec.is_some() {
+            TrackAction::Transcode
+        } else {
+            TrackAction::Copy
+        };
+        let hardlinked = action == TrackAction::Copy
+            && cli_args.copy_mode == CopyMode::Hardlink
+            && can_hardlink(&beatmap_info.audio);
+        plan.tracks.push(PlannedTrack::of(
+            destination_path,
+            beatmap_info,
+            action,
+            !cli_args.skip_info && !hardlinked,
+            &decision.reason,
+        ));
+    }
+    let mut merged: Vec<MergedAudio> = merged_audios
+        .iter()
+        .map(|(audio, kept)| MergedAudio::new(audio.clone(), kept.clone(), MergeReason::Source))
+        .chain(deduped_audios.iter().map(|(audio, kept)| {
+            MergedAudio::new(audio.clone(), kept.clone(), MergeReason::Duplicate)
+        }))
+        .collect();
+    merged.sort_by(|x, y| x.audio.cmp(&y.audio));
+    plan.merged = merged;
+    plan.playlists = playlists
+        .iter()
+        .map(|playlist| playlist.path.clone())
+        .collect();
+    if let (true, Some(manifest)) = (cli_args.remove_missing_songs, &manifest) {
+        plan.removals = manifest
+            .exported_files()?
+            .into_iter()
+            .filter(|exported_file| {
+                match planned_files.get(&cli_args.filesystem.collision_key(exported_file)) {
+                    Some(planned_file) => !is_same_file(planned_file, exported_file),
+                    None => true,
+                }
+            })
+            .filter(|exported_file| exported_file.exists())
+            .collect();
+        plan.removals.sort();
+    }
+    plan.collisions = report.collisions.clone();
+    plan.skipped = report.skipped.clone();
+    Ok(plan)
+}
+
+/// Deletes the files an earlier run exported that are no longer part of the
+/// export, and the rewrites an interrupted run left next to exported files,
+/// then the folders it created that this left empty. Anything else in the
+/// destination is never touched.
+fn remove_stale_exports(
+    manifest: &ExportManifest,
+    planned_files: &HashMap<PathBuf, &PathBuf>,
+    filesystem: FilesystemProfile,
+    report: &mut ExportReport,
+) -> Result<(), ExportError> {
+    let exported_files = manifest.exported_files()?;
+    remove_leftover_rewrites(&exported_files, report);
+    for exported_file in exported_files {
+        match planned_files.get(&filesystem.collision_key(&exported_file)) {
+            Some(planned_file) if *planned_file == &exported_file => continue,
+            // Only the case of its name changed, and the filesystem ignores it.
+            Some(planned_file) if is_same_file(planned_file, &exported_file) => {
+                manifest.forget_file(&exported_file)?;
+                continue;
             }
-            .arg(destination_path.to_str().unwrap())
-            .stdout(subprocess::Redirection::Pipe)
-            .stderr(subprocess::Redirection::Pipe)
-            .join()
-            .unwrap();
-        }
-        // std::fs::write(
-        //     &destination_path,
-        //     &std::fs::read(&beatmap_info_holder.audio).unwrap(),
-        // )
-        // .unwrap();
-        let destination_path_clone: PathBuf = destination_path.clone();
-        let beatmap_info_holder_clone: OsuBeatmapInfoHolderSimple = beatmap_info_holder;
-        let skip_info = cli_args.skip_info;
-        let skip_bitmap = cli_args.skip_bitmap;
-        let thread_pool = threadpool::ThreadPool::new(1);
-        thread_pool.execute(move || {
-            update_audio_metadata(
-                &destination_path_clone,
-                &beatmap_info_holder_clone,
-                skip_info,
-                skip_bitmap,
+            _ => (),
+        }
+        match std::fs::remove_file(&exported_file) {
+            Ok(()) => manifest.forget_file(&exported_file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                manifest.forget_file(&exported_file)?
+            }
+            Err(e) => report.skip(&ExportError::io(&exported_file)(e)),
+        }
+    }
+    for created_folder in manifest.created_folders()? {
+        let is_empty = created_folder
+            .read_dir()
+            .map(|mut entries| entries.next().is_none());
+        match is_empty {
+            Ok(true) => match std::fs::remove_dir(&created_folder) {
+                Ok(()) => manifest.forget_folder(&created_folder)?,
+                Err(e) => report.skip(&ExportError::io(&created_folder)(e)),
+            },
+            Ok(false) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                manifest.forget_folder(&created_folder)?
+            }
+            Err(e) => report.skip(&ExportError::io(&created_folder)(e)),
+        }
+    }
+    Ok(())
+}
+
+/// Removes the rewrites that an interrupted run left next to the files it
+/// was tagging.
+fn remove_leftover_rewrites(exported_files: &[PathBuf], report: &mut ExportReport) {
+    let exported_file_set: HashSet<&PathBuf> = exported_files.iter().collect();
+    let exported_folders: HashSet<&Path> = exported_files
+        .iter()
+        .filter_map(|exported_file| exported_file.parent())
+        .collect();
+    for exported_folder in exported_folders {
+        let Ok(entries) = exported_folder.read_dir() else {
+            continue;
+        };
+        for leftover in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            let is_ours = vorbis::tagged_by_leftover(&leftover)
+                .map(|tagged| exported_file_set.contains(&tagged))
+                .unwrap_or(false);
+            if is_ours {
+                if let Err(e) = std::fs::remove_file(&leftover) {
+                    report.skip(&ExportError::io(&leftover)(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(x: &std::path::Path, y: &std::path::Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (x.metadata(), y.metadata()) {
+        (Ok(x), Ok(y)) => x.dev() == y.dev() && x.ino() == y.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(x: &std::path::Path, y: &std::path::Path) -> bool {
+    x.exists() && y.exists()
+}
+
+#[cfg(unix)]
+fn is_same_filesystem(x: &Path, y: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (x.metadata(), y.metadata()) {
+        (Ok(x), Ok(y)) => x.dev() == y.dev(),
+        _ => false,
+    }
+}
+
+/// Tells by the drive, as that is where Windows filesystems start.
+#[cfg(not(unix))]
+fn is_same_filesystem(x: &Path, y: &Path) -> bool {
+    match (x.canonicalize(), y.canonicalize()) {
+        (Ok(x), Ok(y)) => x.components().next() == y.components().next(),
+        _ => false,
+    }
+}
+
+/// Keeps one difficulty per distinct audio file of a set, so each of them is
+/// exported as its own track.
+fn distinct_audio_infos(beatmap_infos: &[OsuBeatmapInfoHolder]) -> Vec<&OsuBeatmapInfoHolder> {
+    let mut seen_audios: HashSet<&PathBuf> = HashSet::new();
+    let mut distinct: Vec<&OsuBeatmapInfoHolder> = beatmap_infos
+        .iter()
+        .filter(|beatmap_info| seen_audios.insert(&beatmap_info.audio))
+        .collect();
+    distinct.sort_by(|x, y| x.audio.cmp(&y.audio));
+    distinct
+}
+
+/// Gives another file name to the tracks that would otherwise be written to
+/// the same file as another track, or over a file that isn't ours, such as
+/// the several audio files of a set that share its title. Tracks are taken
+/// in path then audio order, so the same tracks get the same names each run.
+fn make_destinations_unique(
+    beatmap_copies: &mut [(PathBuf, OsuBeatmapInfoHolderSimple)],
+    strategy: CollisionStrategy,
+    filesystem: FilesystemProfile,
+    is_foreign: &dyn Fn(&PathBuf) -> bool,
+) -> Result<Vec<ResolvedCollision>, ExportError> {
+    beatmap_copies.sort_by_cached_key(|(destination_path, beatmap_info)| {
+        (
+            filesystem.collision_key(destination_path),
+            destination_path.clone(),
+            beatmap_info.audio.clone(),
+        )
+    });
+    let mut taken: HashSet<PathBuf> = beatmap_copies
+        .iter()
+        .map(|(destination_path, _)| filesystem.collision_key(destination_path))
+        .collect();
+    let is_free = |taken: &HashSet<PathBuf>, candidate: &PathBuf| {
+        !taken.contains(&filesystem.collision_key(candidate)) && !is_foreign(candidate)
+    };
+    let mut collisions: Vec<ResolvedCollision> = vec![];
+    let same_name = |x: &(PathBuf, OsuBeatmapInfoHolderSimple),
+                     y: &(PathBuf, OsuBeatmapInfoHolderSimple)| {
+        filesystem.collision_key(&x.0) == filesystem.collision_key(&y.0)
+    };
+    for group in beatmap_copies.chunk_by_mut(same_name) {
+        let is_shared = group.len() > 1;
+        for (position, (destination_path, beatmap_info)) in group.iter_mut().enumerate() {
+            let original = destination_path.clone();
+            // With set IDs, none of the tracks keeps the bare name, so which
+            // name each gets doesn't depend on the others.
+            let collides = (strategy == CollisionStrategy::SetId && is_shared)
+                || position > 0
+                || is_foreign(&original);
+            if !collides {
+                continue;
+            }
+            let mut renamed: Option<PathBuf> = None;
+            if strategy == CollisionStrategy::SetId {
+                let candidate = filesystem
+                    .with_stem_suffix(&original, &format!(" #{}", beatmap_info.beatmapset_id));
+                if is_free(&taken, &candidate) {
+                    renamed = Some(candidate);
+                }
+            }
+            if strategy != CollisionStrategy::Fail && renamed.is_none() {
+                renamed = (2..)
+                    .map(|counter| {
+                        filesystem.with_stem_suffix(&original, &format!(" ({})", counter))
+                    })
+                    .find(|candidate| is_free(&taken, candidate));
+            }
+            let destination = renamed.unwrap_or_else(|| original.clone());
+            taken.insert(filesystem.collision_key(&destination));
+            *destination_path = destination.clone();
+            collisions.push(ResolvedCollision::new(
+                beatmap_info.audio.clone(),
+                original,
+                destination,
+            ));
+        }
+    }
+    if strategy == CollisionStrategy::Fail && !collisions.is_empty() {
+        let listing: Vec<String> = collisions
+            .iter()
+            .map(|collision| format!("{:?} for {:?}", collision.planned, collision.source))
+            .collect();
+        return Err(ExportError::Input(format!(
+            "{} track(s) would overwrite another file: {}",
+            collisions.len(),
+            listing.join(", ")
+        )));
+    }
+    Ok(collisions)
+}
+
+/// Collections whose names end up as the same file name get numbered
+/// playlists, as there is no set ID to tell them apart.
+fn make_playlist_paths_unique(
+    playlists: &mut [M3u8Playlist],
+    strategy: CollisionStrategy,
+    filesystem: FilesystemProfile,
+    is_foreign: &dyn Fn(&PathBuf) -> bool,
+) -> Result<Vec<ResolvedCollision>, ExportError> {
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    let mut collisions: Vec<ResolvedCollision> = vec![];
+    for playlist in playlists.iter_mut() {
+        let original = playlist.path.clone();
+        let is_free = |taken: &HashSet<PathBuf>, candidate: &PathBuf| {
+            !taken.contains(&filesystem.collision_key(candidate)) && !is_foreign(candidate)
+        };
+        if !is_free(&taken, &original) {
+            if strategy != CollisionStrategy::Fail {
+                playlist.path = (2..)
+                    .map(|counter| {
+                        filesystem.with_stem_suffix(&original, &format!(" ({})", counter))
+                    })
+                    .find(|candidate| is_free(&taken, candidate))
+                    .unwrap_or_else(|| original.clone());
+            }
+            collisions.push(ResolvedCollision::new(
+                PathBuf::from(&playlist.collection_name),
+                original,
+                playlist.path.clone(),
+            ));
+        }
+        taken.insert(filesystem.collision_key(&playlist.path));
+    }
+    if strategy == CollisionStrategy::Fail && !collisions.is_empty() {
+        let listing: Vec<String> = collisions
+            .iter()
+            .map(|collision| format!("{:?} for {:?}", collision.planned, collision.source))
+            .collect();
+        return Err(ExportError::Input(format!(
+            "{} playlist(s) would overwrite another file: {}",
+            collisions.len(),
+            listing.join(", ")
+        )));
+    }
+    Ok(collisions)
+}
+
+fn discover_beatmap_set_readers() -> Result<Vec<Box<dyn OsuBeatmapSets>>, ExportError> {
+    let mut beatmap_set_readers: Vec<Box<dyn OsuBeatmapSets>> = vec![];
+    for candidate in osu_installation_candidates() {
+        if let Ok(beatmap_set_reader) = try_beatmap_set_readers(&candidate) {
+            eprintln!("Found osu! installation at {:?}", candidate);
+            beatmap_set_readers.push(beatmap_set_reader);
+        }
+    }
+    if beatmap_set_readers.is_empty() {
+        return Err(ExportError::Input(
+            "No osu! installation was found, please give its folder as OSU_SOURCE".to_string(),
+        ));
+    }
+    Ok(beatmap_set_readers)
+}
+
+fn pick_beatmap_set_reader(osu_source: &PathBuf) -> Result<Box<dyn OsuBeatmapSets>, ExportError> {
+    if !osu_source.is_dir() {
+        return Err(ExportError::Input(format!(
+            "Path {:?} is not a directory",
+            osu_source
+        )));
+    }
+    try_beatmap_set_readers(osu_source).map_err(|beatmap_set_readers_failures| {
+        for beatmap_set_readers_failure in beatmap_set_readers_failures {
+            eprintln!("WARN: {}", beatmap_set_readers_failure);
+        }
+        ExportError::Input(format!(
+            "No healthy osu! folder structure identified at {:?}",
+            osu_source
+        ))
+    })
+}
+
+fn try_beatmap_set_readers(
+    osu_source: &PathBuf,
+) -> Result<Box<dyn OsuBeatmapSets>, Vec<ExportError>> {
+    let beatmap_set_readers_fns: Vec<&FnBeatmapSetReader> = vec![
+        &(|x| Osu40DbBeatmapSetsReader::try_from(x).map(|a| a.boxed())),
+        &(|x| Osu40BeatmapSetsReader::try_from(x).map(|a| a.boxed())),
+        &(|x| Osu50RealmBeatmapSetsReader::try_from(x).map(|a| a.boxed())),
+        &(|x| Osu50BeatmapSetsReader::try_from(x).map(|a| a.boxed())),
+        &(|x| OsuArchiveBeatmapSetsReader::try_from(x).map(|a| a.boxed())),
+    ];
+    let mut beatmap_set_readers_failures: Vec<ExportError> = vec![];
+    for beatmap_set_readers_fn in beatmap_set_readers_fns {
+        match beatmap_set_readers_fn(osu_source) {
+            Ok(beatmap_set_reader) => return Ok(beatmap_set_reader),
+            Err(beatmap_set_readers_failure) => {
+                beatmap_set_readers_failures.push(beatmap_set_readers_failure)
+            }
+        }
+    }
+    Err(beatmap_set_readers_failures)
+}
+
+fn gather_collections(
+    beatmap_set_readers: &[Box<dyn OsuBeatmapSets>],
+) -> Result<Vec<OsuCollection>, ExportError> {
+    let mut collections: Vec<OsuCollection> = vec![];
+    for beatmap_set_reader in beatmap_set_readers {
+        for collection in beatmap_set_reader.collections()? {
+            match collections
+                .iter_mut()
+                .find(|existing| existing.name == collection.name)
+            {
+                Some(existing) => {
+                    for hash in collection.beatmap_hashes {
+                        if !existing.beatmap_hashes.contains(&hash) {
+                            existing.beatmap_hashes.push(hash);
+                        }
+                    }
+                }
+                None => collections.push(collection),
+            }
+        }
+    }
+    Ok(collections)
+}
+
+fn select_collections(
+    collections: &[OsuCollection],
+    collection_names: &[String],
+) -> Result<Vec<OsuCollection>, ExportError> {
+    if collection_names.is_empty() {
+        return Ok(collections.to_vec());
+    }
+    collection_names
+        .iter()
+        .map(|collection_name| {
+            collections
+                .iter()
+                .find(|collection| &collection.name == collection_name)
+                .cloned()
+                .ok_or_else(|| {
+                    ExportError::Input(format!("Collection {:?} was not found", collection_name))
+                })
+        })
+        .collect()
+}
+
+fn do_copy(
+    destination_path: PathBuf,
+    beatmap_info_holder: OsuBeatmapInfoHolderSimple,
+    decision: &TranscodeDecision,
+    cli_args: cli::CliArguments,
+) -> Result<Option<Loudness>, ExportError> {
+    // What is there may be a hardlink to a source, which writing through
+    // would change.
+    match std::fs::remove_file(&destination_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(ExportError::io(&destination_path)(e))
+        }
+        _ => {}
+    }
+    if decision.codec.is_none() {
+        let hardlinked = copy_audio(
+            &beatmap_info_holder.audio,
+            &destination_path,
+            cli_args.copy_mode,
+        )?;
+        if hardlinked {
+            return Ok(None);
+        }
+    } else {
+        let input = FFmpegInput::of(&beatmap_info_holder.audio)?;
+        let mut sps = subprocess::Exec::cmd("ffmpeg")
+            .arg("-y")
+            .arg("-i")
+            .arg(input.arg())
+            .arg("-map")
+            .arg("0:a");
+        if cli_args.loudness == LoudnessMode::Normalize {
+            let gain = ffmpeg_loudness(&beatmap_info_holder.audio)?.normalisation_gain();
+            sps = sps.arg("-filter:a").arg(format!("volume={:.2}dB", gain));
+        }
+        let sps = sps.arg("-c:a");
+        let capture = input
+            .capture(
+                sps.args(&decision.ffmpeg_arguments(cli_args.quality))
+                    .arg(&destination_path)
+                    .stdout(subprocess::Redirection::Pipe)
+                    .stderr(subprocess::Redirection::Pipe),
             )
-        });
-        thread_pool.join();
+            .map_err(|e| {
+                ExportError::ffmpeg(
+                    &beatmap_info_holder.audio,
+                    format!("could not run ffmpeg: {}", e),
+                )
+            })?;
+        if !capture.success() {
+            let stderr = capture.stderr_str();
+            return Err(ExportError::ffmpeg(
+                &beatmap_info_holder.audio,
+                stderr
+                    .lines()
+                    .rev()
+                    .find(|line| !line.trim().is_empty())
+                    .unwrap_or("ffmpeg failed"),
+            ));
+        }
+    }
+    // std::fs::write(
+    //     &destination_path,
+    //     &std::fs::read(&beatmap_info_holder.audio).unwrap(),
+    // )
+    // .unwrap();
+    let destination_path_clone: PathBuf = destination_path.clone();
+    let beatmap_info_holder_clone: OsuBeatmapInfoHolderSimple = beatmap_info_holder;
+    let skip_info = cli_args.skip_info;
+    let skip_bitmap = cli_args.skip_bitmap;
+    let (tx, rx) = std::sync::mpsc::channel::<Result<(), ExportError>>();
+    let thread_pool = threadpool::ThreadPool::new(1);
+    thread_pool.execute(move || {
+        tx.send(update_audio_metadata(
+            &destination_path_clone,
+            &beatmap_info_holder_clone,
+            skip_info,
+            skip_bitmap,
+        ))
+        .unwrap_or(())
+    });
+    thread_pool.join();
+    rx.recv()
+        .unwrap_or_else(|_| Err(ExportError::tag(&destination_path, "tagging panicked")))?;
+    // Normalised tracks need no gain tags.
+    match (cli_args.loudness, decision.codec) {
+        (LoudnessMode::Off, _) | (LoudnessMode::Normalize, Some(_)) => Ok(None),
+        _ => ffmpeg_loudness(&destination_path).map(Some),
     }
 }
 
+/// Copies the source audio as it is, or links it when asked and the
+/// destination is on the same filesystem. Returns whether it was hardlinked.
+fn copy_audio(
+    source: &PathBuf,
+    destination: &PathBuf,
+    copy_mode: CopyMode,
+) -> Result<bool, ExportError> {
+    // Files inside beatmap archives can only be written out.
+    if archive_entry(source).is_some() {
+        std::fs::write(destination, read_source(source)?).map_err(ExportError::io(destination))?;
+        return Ok(false);
+    }
+    if copy_mode == CopyMode::Hardlink && std::fs::hard_link(source, destination).is_ok() {
+        return Ok(true);
+    }
+    match copy_mode {
+        CopyMode::Reflink => reflink_copy::reflink_or_copy(source, destination).map(|_| ()),
+        _ => std::fs::copy(source, destination).map(|_| ()),
+    }
+    .map_err(ExportError::io(destination))?;
+    Ok(false)
+}
+
 fn update_audio_metadata(
     destination_path: &PathBuf,
     beatmap_info_holder: &OsuBeatmapInfoHolderSimple,
     skip_info: bool,
     skip_pic: bool,
-) {
-    if !skip_info {
-        if let Ok(mut tag) = audiotags::Tag::new().read_from_path(destination_path) {
-            tag.remove_album();
-            tag.remove_album_artist();
-            tag.remove_album_cover();
-            tag.remove_album_title();
-            tag.remove_artist();
-            tag.remove_disc();
-            tag.remove_disc_number();
-            tag.remove_title();
-            tag.remove_total_discs();
-            tag.remove_total_tracks();
-            tag.remove_track();
-            tag.remove_track_number();
-            tag.remove_year();
-            tag.set_album_title("osu!");
-            // tag.set_text(format!(
-            //     "https://osu.ppy.sh/beatmapsets/{}",
-            //     beatmap_info_holder.beatmapset_id
-            // ));
-            tag.set_title(&beatmap_info_holder.info.title);
-            tag.set_artist(&beatmap_info_holder.info.artist);
-            if !skip_pic {
-                if let Some(background_source_path) = &beatmap_info_holder.background {
-                    let guessed_format: Option<image::ImageFormat> = beatmap_info_holder
-                        .extensions
-                        .1
-                        .clone()
-                        .and_then(image::ImageFormat::from_extension);
-                    let reader_image_result: Result<image::ImageReader<_>, _> =
-                        image::ImageReader::open(background_source_path);
-                    // reader_image_result.as_ref().unwrap();
-                    if let Ok(reader_image) = reader_image_result {
-                        let reader_image_with_guess: image::ImageReader<_> = match guessed_format {
-                            Some(x) => {
-                                image::ImageReader::with_format(reader_image.into_inner(), x)
-                            }
-                            None => reader_image,
-                        };
-                        let loaded_image_option: Result<image::DynamicImage, _> =
-                            reader_image_with_guess.decode();
-                        // loaded_image_option.as_ref().unwrap();
-                        if let Ok(loaded_image) = loaded_image_option {
-                            let thumbnail = loaded_image.thumbnail(1024, 1024);
-                            let mut bytes_cursor = std::io::Cursor::new(vec![]);
-                            thumbnail
-                                .write_to(&mut bytes_cursor, image::ImageFormat::Png)
-                                .unwrap();
-                            {
-                                let cover = audiotags::Picture {
-                                    mime_type: audiotags::MimeType::Png,
-                                    data: bytes_cursor.get_ref(),
-                                };
-                                tag.set_album_cover(cover.clone());
-                            }
-                        }
-                    }
-                }
+) -> Result<(), ExportError> {
+    if skip_info {
+        return Ok(());
+    }
+    let cover = if skip_pic {
+        None
+    } else {
+        cover_picture(beatmap_info_holder)?
+    };
+    let extension = destination_path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    if let Some("ogg" | "opus") = extension.as_deref() {
+        return vorbis::write_tags(
+            destination_path,
+            &vorbis::TagValues::new(
+                &beatmap_info_holder.tag_info.title,
+                &beatmap_info_holder.tag_info.artist,
+                "osu!",
+                cover.as_ref(),
+            ),
+        );
+    }
+    let tag_result = match audiotags::Tag::new().read_from_path(destination_path) {
+        Ok(tag) => Ok(Some(tag)),
+        Err(audiotags::Error::UnknownFileExtension(_))
+        | Err(audiotags::Error::UnsupportedFormat(_)) => Ok(None),
+        Err(e) => Err(ExportError::tag(destination_path, e)),
+    };
+    if let Some(mut tag) = tag_result? {
+        tag.remove_album();
+        tag.remove_album_artist();
+        tag.remove_album_cover();
+        tag.remove_album_title();
+        tag.remove_artist();
+        tag.remove_disc();
+        tag.remove_disc_number();
+        tag.remove_title();
+        tag.remove_total_discs();
+        tag.remove_total_tracks();
+        tag.remove_track();
+        tag.remove_track_number();
+        tag.remove_year();
+        tag.set_album_title("osu!");
+        // tag.set_text(format!(
+        //     "https://osu.ppy.sh/beatmapsets/{}",
+        //     beatmap_info_holder.beatmapset_id
+        // ));
+        tag.set_title(&beatmap_info_holder.tag_info.title);
+        tag.set_artist(&beatmap_info_holder.tag_info.artist);
+        if let Some(cover) = &cover {
+            tag.set_album_cover(audiotags::Picture {
+                mime_type: audiotags::MimeType::Png,
+                data: &cover.data,
+            });
+        }
+        tag.write_to_path(&destination_path.to_string_lossy())
+            .map_err(|e| ExportError::tag(destination_path, e))?;
+    }
+    Ok(())
+}
+
+/// The beatmap background, shrunk to a PNG cover. A background that can't
+/// be read is left out.
+fn cover_picture(
+    beatmap_info_holder: &OsuBeatmapInfoHolderSimple,
+) -> Result<Option<vorbis::CoverPicture>, ExportError> {
+    if let Some(background_source_path) = &beatmap_info_holder.background {
+        let guessed_format: Option<image::ImageFormat> = beatmap_info_holder
+            .extensions
+            .1
+            .clone()
+            .and_then(image::ImageFormat::from_extension);
+        let reader_image_result = read_source(background_source_path).and_then(|background| {
+            image::ImageReader::new(std::io::Cursor::new(background))
+                .with_guessed_format()
+                .map_err(ExportError::io(background_source_path))
+        });
+        if let Ok(reader_image) = reader_image_result {
+            let reader_image_with_guess: image::ImageReader<_> = match guessed_format {
+                Some(x) => image::ImageReader::with_format(reader_image.into_inner(), x),
+                None => reader_image,
+            };
+            let loaded_image_option: Result<image::DynamicImage, _> =
+                reader_image_with_guess.decode();
+            if let Ok(loaded_image) = loaded_image_option {
+                let thumbnail = loaded_image.thumbnail(1024, 1024);
+                let mut bytes_cursor = std::io::Cursor::new(vec![]);
+                thumbnail
+                    .write_to(&mut bytes_cursor, image::ImageFormat::Png)
+                    .map_err(|e| ExportError::tag(background_source_path, e))?;
+                return Ok(Some(vorbis::CoverPicture::new(
+                    "image/png",
+                    thumbnail.width(),
+                    thumbnail.height(),
+                    bytes_cursor.into_inner(),
+                )));
             }
-            tag.write_to_path(destination_path.to_str().unwrap())
-                .unwrap_or(());
         }
     }
+    Ok(None)
 }
 
-fn ffprobe_audio_duration(file: &PathBuf) -> Option<FFProbeAudioStream> {
-    subprocess::Exec::cmd("ffprobe")
-        .arg("-hide_banner")
-        .arg("-show_format")
-        .arg("-show_streams")
-        .arg("-count_frames")
-        .arg("-count_packets")
-        .arg("-output_format")
-        .arg("json")
-        .arg(&file)
-        .stdout(subprocess::Redirection::Pipe)
-        .stderr(subprocess::Redirection::Pipe)
-        .capture()
-        .ok()
+/// What ffmpeg and ffprobe read a source from. They can't open files inside
+/// beatmap archives, which are piped in instead.
+enum FFmpegInput {
+    Path(PathBuf),
+    Piped(Vec<u8>),
+}
+
+impl FFmpegInput {
+    fn of(file: &Path) -> Result<Self, ExportError> {
+        match archive_entry(file) {
+            Some(_) => read_source(file).map(Self::Piped),
+            None => Ok(Self::Path(file.to_path_buf())),
+        }
+    }
+
+    fn arg(&self) -> &std::ffi::OsStr {
+        match self {
+            Self::Path(path) => path.as_os_str(),
+            Self::Piped(_) => std::ffi::OsStr::new("pipe:0"),
+        }
+    }
+
+    fn capture(&self, exec: subprocess::Exec) -> subprocess::Result<subprocess::CaptureData> {
+        let data = match self {
+            Self::Path(_) => return exec.capture(),
+            Self::Piped(data) => data,
+        };
+        let mut popen = exec.stdin(subprocess::Redirection::Pipe).popen()?;
+        let stdin = popen.stdin.take();
+        std::thread::scope(|scope| {
+            if let Some(mut stdin) = stdin {
+                // ffprobe stops reading once it has seen the headers.
+                scope.spawn(move || std::io::Write::write_all(&mut stdin, data).unwrap_or(()));
+            }
+            let (stdout, stderr) = popen.communicate_bytes(None)?;
+            Ok(subprocess::CaptureData {
+                stdout: stdout.unwrap_or_default(),
+                stderr: stderr.unwrap_or_default(),
+                exit_status: popen.wait()?,
+            })
+        })
+    }
+}
+
+fn ffprobe_audio_duration(file: &Path) -> Result<FFProbeAudioStream, ExportError> {
+    let input = FFmpegInput::of(file)?;
+    input
+        .capture(
+            subprocess::Exec::cmd("ffprobe")
+                .arg("-hide_banner")
+                .arg("-show_format")
+                .arg("-show_streams")
+                .arg("-count_frames")
+                .arg("-count_packets")
+                .arg("-output_format")
+                .arg("json")
+                .arg("-i")
+                .arg(input.arg())
+                .stdout(subprocess::Redirection::Pipe)
+                .stderr(subprocess::Redirection::Pipe),
+        )
+        .map_err(|e| ExportError::ffmpeg(file, format!("could not run ffprobe: {}", e)))
         .and_then(|capture_data| match capture_data.exit_status {
             subprocess::ExitStatus::Exited(0) => {
-                let ffpo = serde_json::from_slice::<FFProbeOutput>(&capture_data.stdout).unwrap();
+                let ffpo = serde_json::from_slice::<FFProbeOutput>(&capture_data.stdout)
+                    .map_err(|e| ExportError::ffmpeg(file, e))?;
                 let audstr = ffpo
                     .streams
                     .iter()
-                    .map(|i| match i {
+                    .filter_map(|i| match i {
                         FFProbeStream::Audio(a) => Some(a),
                         _ => None,
                     })
-                    .flatten()
-                    .next()?;
-                Some((*audstr).clone())
+                    .next()
+                    .ok_or_else(|| ExportError::ffmpeg(file, "no audio stream"))?;
+                Ok((*audstr).clone())
+            }
+            exit_status => Err(ExportError::ffmpeg(
+                file,
+                format!("ffprobe exited with {:?}", exit_status),
+            )),
+        })
+}
+
+/// Runs the file through the EBU R128 meter of ffmpeg.
+fn ffmpeg_loudness(file: &Path) -> Result<Loudness, ExportError> {
+    let input = FFmpegInput::of(file)?;
+    let capture = input
+        .capture(
+            subprocess::Exec::cmd("ffmpeg")
+                .arg("-hide_banner")
+                .arg("-nostats")
+                .arg("-i")
+                .arg(input.arg())
+                .arg("-map")
+                .arg("0:a")
+                .arg("-filter:a")
+                .arg("ebur128=peak=true")
+                .arg("-f")
+                .arg("null")
+                .arg("-")
+                .stdout(subprocess::Redirection::Pipe)
+                .stderr(subprocess::Redirection::Pipe),
+        )
+        .map_err(|e| ExportError::ffmpeg(file, format!("could not run ffmpeg: {}", e)))?;
+    if !capture.success() {
+        return Err(ExportError::ffmpeg(
+            file,
+            format!("ffmpeg exited with {:?}", capture.exit_status),
+        ));
+    }
+    Loudness::from_ffmpeg_output(&capture.stderr_str())
+        .ok_or_else(|| ExportError::ffmpeg(file, "no loudness summary"))
+}
+
+/// Reads only the headers, so unlike `ffprobe_audio_duration` it's quick
+/// enough to run on every track of every export.
+fn ffprobe_audio_summary(file: &Path) -> Result<AudioProbe, ExportError> {
+    let input = FFmpegInput::of(file)?;
+    let capture = input
+        .capture(
+            subprocess::Exec::cmd("ffprobe")
+                .arg("-hide_banner")
+                .arg("-show_format")
+                .arg("-show_streams")
+                .arg("-output_format")
+                .arg("json")
+                .arg("-i")
+                .arg(input.arg())
+                .stdout(subprocess::Redirection::Pipe)
+                .stderr(subprocess::Redirection::Pipe),
+        )
+        .map_err(|e| ExportError::ffmpeg(file, format!("could not run ffprobe: {}", e)))?;
+    if !capture.success() {
+        return Err(ExportError::ffmpeg(
+            file,
+            format!("ffprobe exited with {:?}", capture.exit_status),
+        ));
+    }
+    let summary = serde_json::from_slice::<FFProbeSummary>(&capture.stdout)
+        .map_err(|e| ExportError::ffmpeg(file, e))?;
+    let stream = summary
+        .streams
+        .iter()
+        .find(|stream| stream.codec_type == "audio")
+        .ok_or_else(|| ExportError::ffmpeg(file, "no audio stream"))?;
+    let file_size = source_len(file).ok_or_else(|| ExportError::not_found(file))?;
+    Ok(AudioProbe::new(
+        stream
+            .codec_name
+            .as_deref()
+            .and_then(|codec| OutputCodec::from_str(codec).ok()),
+        stream
+            .bit_rate
+            .as_ref()
+            .or(summary.format.bit_rate.as_ref())
+            .and_then(|bit_rate| bit_rate.parse::<u32>().ok())
+            .map(|bit_rate| bit_rate.div_ceil(1000)),
+        stream
+            .sample_rate
+            .as_ref()
+            .and_then(|sample_rate| sample_rate.parse().ok()),
+        file_size,
+    ))
+}
+
+/// Decides how each audio file is exported. Under a policy every file is
+/// probed, and one that can't be is transcoded to be safe.
+fn decide_transcoding(
+    beatmap_infos: &[OsuBeatmapInfoHolderSimple],
+    cli_args: &CliArguments,
+) -> HashMap<PathBuf, TranscodeDecision> {
+    let (policy, target) = match (cli_args.transcode_policy(), cli_args.codec) {
+        (Some(policy), Some(target)) => (policy, target),
+        _ => {
+            let decision = TranscodeDecision::fixed(cli_args.codec);
+            return beatmap_infos
+                .iter()
+                .map(|info| (info.audio.clone(), decision.clone()))
+                .collect();
+        }
+    };
+    let (tx, rx) = std::sync::mpsc::channel::<(PathBuf, TranscodeDecision)>();
+    let thread_pool = threadpool::ThreadPool::new(
+        std::thread::available_parallelism()
+            .map(|x| x.get())
+            .unwrap_or(2)
+            * 2,
+    );
+    for info in beatmap_infos.iter() {
+        let audio = info.audio.clone();
+        let policy = policy.clone();
+        let txc = tx.clone();
+        thread_pool.execute(move || {
+            let decision = match ffprobe_audio_summary(&audio) {
+                Ok(probe) => policy.decide(&probe, target),
+                Err(e) => TranscodeDecision::new(
+                    Some(target),
+                    None,
+                    format!("could not be probed: {}", e.reason()),
+                ),
+            };
+            txc.send((audio, decision)).unwrap_or(())
+        });
+    }
+    drop(tx);
+    let decisions = rx.iter().collect();
+    thread_pool.join();
+    decisions
+}
+
+fn audio_quality_score(audio_bitrate: u32, audio_format: FFProbeAudioStreamCodec) -> u64 {
+    (audio_bitrate as u64)
+        * match audio_format {
+            FFProbeAudioStreamCodec::MP3 => 8,
+            FFProbeAudioStreamCodec::Vorbis => 10,
+        }
+}
+
+fn audio_content_hash(audio: &Path) -> Option<String> {
+    read_source(audio)
+        .ok()
+        .map(|content| format!("{:x}", md5::compute(content)))
+}
+
+/// Merges the beatmaps of every source, dropping those that an earlier or
+/// better source already provides with the same beatmapset ID or the same
+/// audio content. Returns the merged infos, along with which audio file
+/// replaced each audio file that was dropped.
+fn merge_beatmap_sources(
+    beatmap_infos_per_source: Vec<Vec<OsuBeatmapInfoHolderSimple>>,
+    source_priority: SourcePriority,
+) -> (Vec<OsuBeatmapInfoHolderSimple>, HashMap<PathBuf, PathBuf>) {
+    if beatmap_infos_per_source.len() <= 1 {
+        return (
+            beatmap_infos_per_source.into_iter().flatten().collect(),
+            HashMap::new(),
+        );
+    }
+    let candidates: Vec<(usize, OsuBeatmapInfoHolderSimple)> = beatmap_infos_per_source
+        .into_iter()
+        .enumerate()
+        .flat_map(|(source_index, beatmap_infos)| {
+            beatmap_infos
+                .into_iter()
+                .map(move |beatmap_info| (source_index, beatmap_info))
+        })
+        .collect();
+    let mut parents: Vec<usize> = (0..candidates.len()).collect();
+    fn find_root(parents: &mut [usize], index: usize) -> usize {
+        let mut root = index;
+        while parents[root] != root {
+            root = parents[root];
+        }
+        parents[index] = root;
+        root
+    }
+    let union = |parents: &mut Vec<usize>, indexes: &[usize]| {
+        if let Some(first) = indexes.first() {
+            let first_root = find_root(parents, *first);
+            for index in indexes.iter().skip(1) {
+                let root = find_root(parents, *index);
+                parents[root] = first_root;
             }
-            _ => None,
+        }
+    };
+    // Sets with several tracks can't be paired by their ID alone, so only
+    // their audio content is compared.
+    let mut tracks_per_set: HashMap<(usize, &BeatmapSetId), usize> = HashMap::new();
+    for (source_index, beatmap_info) in candidates.iter() {
+        *tracks_per_set
+            .entry((*source_index, &beatmap_info.beatmapset_id))
+            .or_default() += 1;
+    }
+    let mut by_beatmapset_id: HashMap<&BeatmapSetId, Vec<usize>> = HashMap::new();
+    let mut by_audio_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (index, (source_index, beatmap_info)) in candidates.iter().enumerate() {
+        if tracks_per_set[&(*source_index, &beatmap_info.beatmapset_id)] == 1 {
+            by_beatmapset_id
+                .entry(&beatmap_info.beatmapset_id)
+                .or_default()
+                .push(index);
+        }
+        if let Some(len) = source_len(&beatmap_info.audio) {
+            by_audio_size.entry(len).or_default().push(index);
+        }
+    }
+    for indexes in by_beatmapset_id.values() {
+        union(&mut parents, indexes);
+    }
+    for indexes in by_audio_size.values().filter(|indexes| indexes.len() > 1) {
+        let mut by_content_hash: HashMap<String, Vec<usize>> = HashMap::new();
+        for index in indexes {
+            if let Some(hash) = audio_content_hash(&candidates[*index].1.audio) {
+                by_content_hash.entry(hash).or_default().push(*index);
+            }
+        }
+        for same_content in by_content_hash.values() {
+            union(&mut parents, same_content);
+        }
+    }
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for index in 0..candidates.len() {
+        let root = find_root(&mut parents, index);
+        groups.entry(root).or_default().push(index);
+    }
+    let mut group_list: Vec<Vec<usize>> = groups.into_values().collect();
+    group_list.sort();
+    let mut merged_audios = HashMap::<PathBuf, PathBuf>::new();
+    let merged: Vec<OsuBeatmapInfoHolderSimple> = group_list
+        .into_iter()
+        .map(|group| {
+            let score = |index: usize| -> u64 {
+                match source_priority {
+                    SourcePriority::Order => 0,
+                    SourcePriority::Quality if group.len() > 1 => {
+                        ffprobe_audio_duration(&candidates[index].1.audio)
+                            .ok()
+                            .and_then(|ffpas| {
+                                Some(audio_quality_score(
+                                    ffpas.bit_rate.parse::<u32>().ok()?,
+                                    ffpas.codec_name,
+                                ))
+                            })
+                            .unwrap_or(0)
+                    }
+                    SourcePriority::Quality => 0,
+                }
+            };
+            let winner_index = group
+                .iter()
+                .map(|index| (*index, score(*index)))
+                .max_by(|(x, x_score), (y, y_score)| {
+                    x_score
+                        .cmp(y_score)
+                        .then(candidates[*y].0.cmp(&candidates[*x].0))
+                        .then(y.cmp(x))
+                })
+                .map(|(index, _)| index)
+                .unwrap_or(group[0]);
+            let mut winner = candidates[winner_index].1.clone();
+            for index in group.iter() {
+                let member = &candidates[*index].1;
+                if winner.background.is_none() && member.background.is_some() {
+                    winner.background = member.background.clone();
+                    winner.extensions.1 = member.extensions.1.clone();
+                }
+                if member.audio != winner.audio {
+                    merged_audios.insert(member.audio.clone(), winner.audio.clone());
+                }
+            }
+            winner
         })
+        .collect();
+    (merged, merged_audios)
+}
+
+fn track_info_from_ffprobe(
+    info: &OsuBeatmapInfoHolderSimple,
+    ffpas: &FFProbeAudioStream,
+) -> Option<OsuBeatmapTrackInfo> {
+    let bit_rate = ffpas.bit_rate.parse::<u32>().ok()?;
+    let sample_rate = ffpas.sample_rate.parse::<u32>().ok()?;
+    let audio_format = ffpas.codec_name;
+    let mut time_base_part = ffpas.time_base.split('/');
+    let time_base_up = time_base_part.next()?.parse::<f64>().ok()?;
+    let time_base_dw = time_base_part.next()?.parse::<f64>().ok()?;
+    let duration_sec = (ffpas.duration_ts as f64) * time_base_up / time_base_dw;
+    Some(OsuBeatmapTrackInfo::new(
+        info.clone(),
+        duration_sec,
+        bit_rate,
+        sample_rate,
+        audio_format,
+    ))
 }
 
-fn deduplicate_infos(duplicated: &[OsuBeatmapInfoHolderSimple]) -> Vec<OsuBeatmapInfoHolderSimple> {
-    let mut ffpas_map = HashMap::<PathBuf, FFProbeAudioStream>::new();
+/// Returns the deduplicated infos, along with which audio file replaced each
+/// audio file that was merged away, and why the audio files that couldn't be
+/// probed were left out.
+fn deduplicate_infos(
+    duplicated: &[OsuBeatmapInfoHolderSimple],
+) -> (
+    Vec<OsuBeatmapInfoHolderSimple>,
+    HashMap<PathBuf, PathBuf>,
+    Vec<ExportError>,
+) {
+    let mut ffpas_map = HashMap::<PathBuf, Result<FFProbeAudioStream, ExportError>>::new();
     {
-        let (tx, rx) = std::sync::mpsc::channel::<(PathBuf, FFProbeAudioStream)>();
+        let (tx, rx) =
+            std::sync::mpsc::channel::<(PathBuf, Result<FFProbeAudioStream, ExportError>)>();
         {
             let tp = threadpool::ThreadPool::new(
                 std::thread::available_parallelism()
-                    .and_then(|x| Ok(x.get()))
+                    .map(|x| x.get())
                     .unwrap_or(2)
                     * 2,
             );
@@ -298,11 +1480,9 @@ fn deduplicate_infos(duplicated: &[OsuBeatmapInfoHolderSimple]) -> Vec<OsuBeatma
                 let aud = bm.audio.clone();
                 let txc = tx.clone();
                 tp.execute(move || {
-                    if let Some(ffpas) = ffprobe_audio_duration(&aud) {
-                        txc.send((aud, ffpas)).unwrap()
-                    }
+                    let ffpas = ffprobe_audio_duration(&aud);
+                    txc.send((aud, ffpas)).unwrap_or(());
                     drop(txc);
-                    ()
                 });
             }
             tp.join();
@@ -313,31 +1493,38 @@ fn deduplicate_infos(duplicated: &[OsuBeatmapInfoHolderSimple]) -> Vec<OsuBeatma
             ffpas_map.insert(k, v);
         }
     }
+    let mut unprobed: Vec<ExportError> = vec![];
     let comparables: Vec<_> = duplicated
         .iter()
-        .map(|info| {
-            let ffpas = ffpas_map.get(&info.audio)?;
-            let bit_rate = ffpas.bit_rate.parse::<u32>().ok()?;
-            let sample_rate = ffpas.sample_rate.parse::<u32>().ok()?;
-            let audio_format = ffpas.codec_name;
-            let mut time_base_part = ffpas.time_base.split('/');
-            let time_base_up = time_base_part.next()?.parse::<f64>().ok()?;
-            let time_base_dw = time_base_part.next()?.parse::<f64>().ok()?;
-            let duration_sec = (ffpas.duration_ts as f64) * time_base_up / time_base_dw;
-            Some(OsuBeatmapTrackInfo::new(
-                info.clone(),
-                duration_sec,
-                bit_rate,
-                sample_rate,
-                audio_format,
-            ))
+        .filter_map(|info| {
+            let ffpas = match ffpas_map.get(&info.audio)? {
+                Ok(ffpas) => ffpas,
+                Err(e) => {
+                    unprobed.push(ExportError::ffmpeg(&info.audio, e.reason()));
+                    return None;
+                }
+            };
+            let track_info = track_info_from_ffprobe(info, ffpas);
+            if track_info.is_none() {
+                unprobed.push(ExportError::ffmpeg(
+                    &info.audio,
+                    "ffprobe reported an unreadable audio stream",
+                ));
+            }
+            track_info
         })
-        .flatten()
         .collect();
     let mut groups: Vec<Vec<&OsuBeatmapTrackInfo>> = vec![];
     for item in comparables.iter() {
         let mut belongs_to: Vec<usize> = vec![];
         for (groupid, group) in groups.iter().enumerate() {
+            // The tracks of a single set are never duplicates of each other.
+            if group
+                .iter()
+                .any(|sample| sample.info.beatmapset_id == item.info.beatmapset_id)
+            {
+                continue;
+            }
             for sample in group.iter() {
                 if ((item.info.info_pair.ascii.title.to_lowercase()
                     == sample.info.info_pair.ascii.title.to_lowercase()
@@ -367,9 +1554,10 @@ fn deduplicate_infos(duplicated: &[OsuBeatmapInfoHolderSimple]) -> Vec<OsuBeatma
             }
         }
     }
+    let mut surviving_audios = HashMap::<PathBuf, PathBuf>::new();
     let chosens: Vec<_> = groups
         .iter()
-        .map(|group| match group.len() {
+        .filter_map(|group| match group.len() {
             0 => None,
             1 => Some(group[0].info.clone()),
             _ => {
@@ -384,34 +1572,29 @@ fn deduplicate_infos(duplicated: &[OsuBeatmapInfoHolderSimple]) -> Vec<OsuBeatma
                     }
                 });
                 let mut group_iter = cgroup.iter();
-                let mut best = group_iter.next().unwrap();
-                while let Some(candidate) = group_iter.next() {
-                    let bbrsc = (best.audio_bitrate as u64)
-                        * match best.audio_format {
-                            FFProbeAudioStreamCodec::MP3 => 8,
-                            FFProbeAudioStreamCodec::VORBIS => 10,
-                        };
-                    let cbrsc = (candidate.audio_bitrate as u64)
-                        * match candidate.audio_format {
-                            FFProbeAudioStreamCodec::MP3 => 8,
-                            FFProbeAudioStreamCodec::VORBIS => 10,
-                        };
-                    if bbrsc < cbrsc {
-                        best = candidate;
-                    } else if bbrsc == cbrsc
-                        && best.info.beatmapset_id < candidate.info.beatmapset_id
+                let mut best = group_iter.next()?;
+                for candidate in group_iter {
+                    let bbrsc = audio_quality_score(best.audio_bitrate, best.audio_format);
+                    let cbrsc =
+                        audio_quality_score(candidate.audio_bitrate, candidate.audio_format);
+                    if bbrsc < cbrsc
+                        || (bbrsc == cbrsc
+                            && best.info.beatmapset_id < candidate.info.beatmapset_id)
                     {
                         best = candidate;
                     }
                 }
-                let latest_background =
-                    cgroup.iter().filter(|x| x.info.background.is_some()).next();
+                for member in cgroup.iter() {
+                    surviving_audios.insert(member.info.audio.clone(), best.info.audio.clone());
+                }
+                let latest_background = cgroup.iter().find(|x| x.info.background.is_some());
                 let best_mix = OsuBeatmapInfoHolderSimple::new(
                     best.info.info.clone(),
+                    best.info.tag_info.clone(),
                     best.info.info_pair.clone(),
                     latest_background
-                        .and_then(|x| Some(x.info.beatmapset_id))
-                        .unwrap_or(best.info.beatmapset_id),
+                        .map(|x| x.info.beatmapset_id.clone())
+                        .unwrap_or(best.info.beatmapset_id.clone()),
                     latest_background.and_then(|x| x.info.background.clone()),
                     best.info.audio.clone(),
                     best.info.beatmap.clone(),
@@ -419,11 +1602,159 @@ fn deduplicate_infos(duplicated: &[OsuBeatmapInfoHolderSimple]) -> Vec<OsuBeatma
                         best.info.extensions.0.clone(),
                         latest_background.and_then(|x| x.info.extensions.1.clone()),
                     ),
+                    best.info.details.clone(),
+                    best.info.unicode_fields.clone(),
                 );
                 Some(best_mix)
             }
         })
-        .flatten()
         .collect();
-    chosens
+    (chosens, surviving_audios, unprobed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy(destination: &str, set_id: u64, audio: &str) -> (PathBuf, OsuBeatmapInfoHolderSimple) {
+        let info = BasicSongInfo::new("Title".to_string(), "Artist".to_string());
+        (
+            PathBuf::from(destination),
+            OsuBeatmapInfoHolderSimple::new(
+                info.clone(),
+                info.clone(),
+                BasicSongInfoPair::new(info.clone(), info),
+                BeatmapSetId::Online(set_id),
+                None,
+                PathBuf::from(audio),
+                PathBuf::new(),
+                (Some("mp3".to_string()), None),
+                BeatmapDetails::default(),
+                vec![],
+            ),
+        )
+    }
+
+    fn unique_destinations(
+        mut beatmap_copies: Vec<(PathBuf, OsuBeatmapInfoHolderSimple)>,
+        strategy: CollisionStrategy,
+        filesystem: FilesystemProfile,
+    ) -> Vec<String> {
+        let is_foreign = |path: &PathBuf| path == Path::new("out/taken.mp3");
+        make_destinations_unique(&mut beatmap_copies, strategy, filesystem, &is_foreign).unwrap();
+        beatmap_copies
+            .iter()
+            .map(|(destination_path, _)| destination_path.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn suffixes_all_but_the_first_track() {
+        let destinations = unique_destinations(
+            vec![
+                copy("out/a.mp3", 2, "2/a.mp3"),
+                copy("out/b.mp3", 3, "3/b.mp3"),
+                copy("out/a.mp3", 1, "1/a.mp3"),
+            ],
+            CollisionStrategy::Suffix,
+            FilesystemProfile::Posix,
+        );
+        assert_eq!(
+            destinations,
+            vec!["out/a.mp3", "out/a (2).mp3", "out/b.mp3"]
+        );
+    }
+
+    #[test]
+    fn gives_every_colliding_track_its_set_id() {
+        let destinations = unique_destinations(
+            vec![
+                copy("out/a.mp3", 2, "2/a.mp3"),
+                copy("out/a.mp3", 1, "1/a.mp3"),
+                copy("out/b.mp3", 3, "3/b.mp3"),
+            ],
+            CollisionStrategy::SetId,
+            FilesystemProfile::Posix,
+        );
+        assert_eq!(
+            destinations,
+            vec!["out/a #1.mp3", "out/a #2.mp3", "out/b.mp3"]
+        );
+        // Adding a colliding set doesn't rename the others.
+        let destinations = unique_destinations(
+            vec![
+                copy("out/a.mp3", 2, "2/a.mp3"),
+                copy("out/a.mp3", 1, "1/a.mp3"),
+                copy("out/a.mp3", 0, "0/a.mp3"),
+            ],
+            CollisionStrategy::SetId,
+            FilesystemProfile::Posix,
+        );
+        assert_eq!(
+            destinations,
+            vec!["out/a #0.mp3", "out/a #1.mp3", "out/a #2.mp3"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_counters_within_a_set() {
+        let destinations = unique_destinations(
+            vec![
+                copy("out/a.mp3", 1, "1/a.mp3"),
+                copy("out/a.mp3", 1, "1/b.mp3"),
+            ],
+            CollisionStrategy::SetId,
+            FilesystemProfile::Posix,
+        );
+        assert_eq!(destinations, vec!["out/a #1.mp3", "out/a (2).mp3"]);
+    }
+
+    #[test]
+    fn ignores_case_where_the_filesystem_does() {
+        let beatmap_copies = vec![
+            copy("out/A.mp3", 1, "1/a.mp3"),
+            copy("out/a.mp3", 2, "2/a.mp3"),
+        ];
+        assert_eq!(
+            unique_destinations(
+                beatmap_copies.clone(),
+                CollisionStrategy::Suffix,
+                FilesystemProfile::Posix
+            ),
+            vec!["out/A.mp3", "out/a.mp3"]
+        );
+        assert_eq!(
+            unique_destinations(
+                beatmap_copies,
+                CollisionStrategy::Suffix,
+                FilesystemProfile::Windows
+            ),
+            vec!["out/A.mp3", "out/a (2).mp3"]
+        );
+    }
+
+    #[test]
+    fn avoids_files_it_did_not_export() {
+        let destinations = unique_destinations(
+            vec![copy("out/taken.mp3", 1, "1/a.mp3")],
+            CollisionStrategy::Suffix,
+            FilesystemProfile::Posix,
+        );
+        assert_eq!(destinations, vec!["out/taken (2).mp3"]);
+    }
+
+    #[test]
+    fn fails_on_collisions_when_asked() {
+        let mut beatmap_copies = vec![
+            copy("out/a.mp3", 1, "1/a.mp3"),
+            copy("out/a.mp3", 2, "2/a.mp3"),
+        ];
+        let result = make_destinations_unique(
+            &mut beatmap_copies,
+            CollisionStrategy::Fail,
+            FilesystemProfile::Posix,
+            &|_| false,
+        );
+        assert!(result.is_err());
+    }
 }
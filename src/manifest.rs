@@ -0,0 +1,479 @@
+use super::cli::CliArguments;
+use super::codec::TranscodeDecision;
+use super::error::*;
+use super::loudness::Loudness;
+use super::model::archive_entry;
+use super::model::OsuBeatmapInfoHolderSimple;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Remembers, in the destination folder, what every exported file was made
+// from and how, so later runs only redo the tracks that changed.
+
+pub const MANIFEST_FILE_NAME: &str = ".osu-songs-exporter.sqlite";
+
+const STMT_CREATE_EXPORTED_FILES: &str = r#"
+CREATE TABLE IF NOT EXISTS ExportedFiles (
+    Path TEXT PRIMARY KEY NOT NULL,
+    Source TEXT NOT NULL,
+    Settings TEXT NOT NULL,
+    Size INTEGER NOT NULL,
+    Decision TEXT NOT NULL DEFAULT '',
+    Loudness REAL,
+    TruePeak REAL,
+    Duration REAL,
+    GainTags TEXT NOT NULL DEFAULT ''
+)
+"#;
+
+/// Columns that manifests of earlier versions lack.
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    (
+        "Decision",
+        "ALTER TABLE ExportedFiles ADD COLUMN Decision TEXT NOT NULL DEFAULT ''",
+    ),
+    (
+        "Loudness",
+        "ALTER TABLE ExportedFiles ADD COLUMN Loudness REAL",
+    ),
+    (
+        "TruePeak",
+        "ALTER TABLE ExportedFiles ADD COLUMN TruePeak REAL",
+    ),
+    (
+        "Duration",
+        "ALTER TABLE ExportedFiles ADD COLUMN Duration REAL",
+    ),
+    (
+        "GainTags",
+        "ALTER TABLE ExportedFiles ADD COLUMN GainTags TEXT NOT NULL DEFAULT ''",
+    ),
+];
+
+const STMT_CREATE_CREATED_FOLDERS: &str = r#"
+CREATE TABLE IF NOT EXISTS CreatedFolders (
+    Path TEXT PRIMARY KEY NOT NULL
+)
+"#;
+
+const STMT_SELECT_EXPORTED_FILE: &str = r#"
+SELECT Source, Settings, Size FROM ExportedFiles WHERE Path = ?1
+"#;
+
+const STMT_UPSERT_EXPORTED_FILE: &str = r#"
+INSERT INTO ExportedFiles (Path, Source, Settings, Size, Decision, Loudness, TruePeak, Duration, GainTags)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, '')
+ON CONFLICT (Path) DO UPDATE SET Source = ?2, Settings = ?3, Size = ?4, Decision = ?5,
+    Loudness = ?6, TruePeak = ?7, Duration = ?8, GainTags = ''
+"#;
+
+const STMT_SELECT_LOUDNESS: &str = r#"
+SELECT Loudness, TruePeak, Duration, GainTags FROM ExportedFiles WHERE Path = ?1
+"#;
+
+const STMT_UPDATE_GAIN_TAGS: &str = r#"
+UPDATE ExportedFiles SET Size = ?2, GainTags = ?3 WHERE Path = ?1
+"#;
+
+const STMT_SELECT_EXPORTED_FILES: &str = r#"
+SELECT Path FROM ExportedFiles
+"#;
+
+const STMT_DELETE_EXPORTED_FILE: &str = r#"
+DELETE FROM ExportedFiles WHERE Path = ?1
+"#;
+
+const STMT_INSERT_CREATED_FOLDER: &str = r#"
+INSERT OR IGNORE INTO CreatedFolders (Path) VALUES (?1)
+"#;
+
+const STMT_SELECT_CREATED_FOLDERS: &str = r#"
+SELECT Path FROM CreatedFolders
+"#;
+
+const STMT_DELETE_CREATED_FOLDER: &str = r#"
+DELETE FROM CreatedFolders WHERE Path = ?1
+"#;
+
+/// What a track is made from and how, as stored in the manifest, along with
+/// why it was copied or transcoded.
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct ExportFingerprint {
+    pub source: String,
+    pub settings: String,
+    pub decision: String,
+}
+
+impl ExportFingerprint {
+    pub fn of(
+        beatmap_info: &OsuBeatmapInfoHolderSimple,
+        decision: &TranscodeDecision,
+        cli_args: &CliArguments,
+    ) -> Self {
+        let settings = format!(
+            "{:?}",
+            (
+                &cli_args.filename_template,
+                decision.codec,
+                decision.sample_rate,
+                cli_args.copy_mode,
+                cli_args.quality,
+                cli_args.loudness,
+                cli_args.skip_info,
+                cli_args.skip_bitmap,
+                &beatmap_info.info,
+                &beatmap_info.tag_info,
+                &beatmap_info.unicode_fields,
+                beatmap_info
+                    .background
+                    .as_ref()
+                    .filter(|_| !cli_args.skip_info && !cli_args.skip_bitmap)
+                    .map(|background| source_identity(background)),
+            )
+        );
+        Self::new(
+            source_identity(&beatmap_info.audio),
+            format!("{:x}", md5::compute(settings)),
+            decision.reason.clone(),
+        )
+    }
+    pub fn playlist() -> Self {
+        Self::new("playlist".to_string(), String::new(), String::new())
+    }
+}
+
+/// osu!lazer names its files after their SHA-256, which identifies them
+/// wherever the storage is; other files are known by path, size and mtime.
+fn source_identity(path: &Path) -> String {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if file_name.len() == 64 && file_name.chars().all(|c| c.is_ascii_hexdigit()) {
+        return format!("sha256:{}", file_name.to_lowercase());
+    }
+    // Files inside beatmap archives change along with their archive.
+    let (size, mtime) = archive_entry(path)
+        .map(|(archive, _)| archive)
+        .unwrap_or(path)
+        .metadata()
+        .map(|metadata| {
+            (
+                metadata.len(),
+                metadata
+                    .modified()
+                    .ok()
+                    .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|mtime| mtime.as_nanos())
+                    .unwrap_or(0),
+            )
+        })
+        .unwrap_or((0, 0));
+    format!("file:{}:{}:{}", path.to_string_lossy(), size, mtime)
+}
+
+pub struct ExportManifest {
+    root: PathBuf,
+    path: PathBuf,
+    connection: rusqlite::Connection,
+}
+
+impl ExportManifest {
+    pub fn open(root: &Path) -> Result<Self, ExportError> {
+        let path = root.join(MANIFEST_FILE_NAME);
+        let connection =
+            rusqlite::Connection::open(&path).map_err(|e| ExportError::database(&path, e))?;
+        connection
+            .execute_batch("PRAGMA synchronous = NORMAL;")
+            .and_then(|_| connection.execute(STMT_CREATE_EXPORTED_FILES, []))
+            .and_then(|_| connection.execute(STMT_CREATE_CREATED_FOLDERS, []))
+            .map_err(|e| ExportError::database(&path, e))?;
+        for (column, statement) in ADDED_COLUMNS.iter() {
+            let has_column = connection
+                .prepare(&format!("SELECT {} FROM ExportedFiles LIMIT 0", column))
+                .is_ok();
+            if !has_column {
+                connection
+                    .execute(statement, [])
+                    .map_err(|e| ExportError::database(&path, e))?;
+            }
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            path,
+            connection,
+        })
+    }
+
+    /// Opens the manifest of an earlier export without writing anything, if
+    /// there is one.
+    pub fn open_existing(root: &Path) -> Result<Option<Self>, ExportError> {
+        let path = root.join(MANIFEST_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let connection = rusqlite::Connection::open_with_flags(
+            &path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .map_err(|e| ExportError::database(&path, e))?;
+        Ok(Some(Self {
+            root: root.to_path_buf(),
+            path,
+            connection,
+        }))
+    }
+
+    /// Manifest entries are relative to the destination, so it keeps working
+    /// when that is mounted somewhere else.
+    fn key(&self, file: &Path) -> String {
+        file.strip_prefix(&self.root)
+            .unwrap_or(file)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Whether the file was already exported from the same source with the
+    /// same settings, and is still there untouched.
+    pub fn is_up_to_date(&self, file: &Path, fingerprint: &ExportFingerprint) -> bool {
+        let recorded: Option<(String, String, i64)> = self
+            .connection
+            .query_row(STMT_SELECT_EXPORTED_FILE, [self.key(file)], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .ok();
+        match recorded {
+            Some((source, settings, size)) => {
+                source == fingerprint.source
+                    && settings == fingerprint.settings
+                    && file
+                        .metadata()
+                        .map(|metadata| metadata.is_file() && metadata.len() as i64 == size)
+                        .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    /// Records a file once it has been fully written, so an interrupted run
+    /// leaves it to be exported again, along with its loudness if scanned.
+    pub fn record(
+        &self,
+        file: &Path,
+        fingerprint: &ExportFingerprint,
+        loudness: Option<&Loudness>,
+    ) -> Result<(), ExportError> {
+        let size = file.metadata().map_err(ExportError::io(file))?.len() as i64;
+        self.connection
+            .execute(
+                STMT_UPSERT_EXPORTED_FILE,
+                rusqlite::params![
+                    self.key(file),
+                    fingerprint.source,
+                    fingerprint.settings,
+                    size,
+                    fingerprint.decision,
+                    loudness.map(|loudness| loudness.integrated),
+                    loudness.map(|loudness| loudness.true_peak),
+                    loudness.map(|loudness| loudness.duration),
+                ],
+            )
+            .map_err(|e| ExportError::database(&self.path, e))?;
+        Ok(())
+    }
+
+    /// The loudness recorded for a file, and the summary of the gain tags
+    /// last written to it.
+    pub fn loudness(&self, file: &Path) -> Option<(Loudness, String)> {
+        type Row = (Option<f64>, Option<f64>, Option<f64>, String);
+        let (integrated, true_peak, duration, gain_tags): Row = self
+            .connection
+            .query_row(STMT_SELECT_LOUDNESS, [self.key(file)], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .ok()?;
+        Some((Loudness::new(integrated?, true_peak?, duration?), gain_tags))
+    }
+
+    /// Records that gain tags were written to a file, which changed its size.
+    pub fn record_gain_tags(&self, file: &Path, summary: &str) -> Result<(), ExportError> {
+        let size = file.metadata().map_err(ExportError::io(file))?.len() as i64;
+        self.connection
+            .execute(
+                STMT_UPDATE_GAIN_TAGS,
+                rusqlite::params![self.key(file), size, summary],
+            )
+            .map_err(|e| ExportError::database(&self.path, e))?;
+        Ok(())
+    }
+
+    fn list(&self, statement: &str) -> Result<Vec<PathBuf>, ExportError> {
+        let mut stmt = self
+            .connection
+            .prepare(statement)
+            .map_err(|e| ExportError::database(&self.path, e))?;
+        let keys = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .and_then(|rows| rows.collect::<Result<Vec<String>, _>>())
+            .map_err(|e| ExportError::database(&self.path, e))?;
+        Ok(keys.into_iter().map(|key| self.root.join(key)).collect())
+    }
+
+    fn forget(&self, statement: &str, path: &Path) -> Result<(), ExportError> {
+        self.connection
+            .execute(statement, [self.key(path)])
+            .map_err(|e| ExportError::database(&self.path, e))?;
+        Ok(())
+    }
+
+    /// Every file this tool wrote into the destination and still owns.
+    pub fn exported_files(&self) -> Result<Vec<PathBuf>, ExportError> {
+        self.list(STMT_SELECT_EXPORTED_FILES)
+    }
+
+    pub fn forget_file(&self, file: &Path) -> Result<(), ExportError> {
+        self.forget(STMT_DELETE_EXPORTED_FILE, file)
+    }
+
+    /// Creates the missing folders up to `folder`, remembering each of them
+    /// so they can be cleaned up once empty.
+    pub fn create_folders(&self, folder: &Path) -> Result<(), ExportError> {
+        let missing: Vec<&Path> = folder
+            .ancestors()
+            .take_while(|ancestor| !ancestor.exists())
+            .collect();
+        for ancestor in missing.into_iter().rev() {
+            std::fs::create_dir(ancestor).map_err(ExportError::io(ancestor))?;
+            if ancestor.starts_with(&self.root) {
+                self.connection
+                    .execute(STMT_INSERT_CREATED_FOLDER, [self.key(ancestor)])
+                    .map_err(|e| ExportError::database(&self.path, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Folders this tool created in the destination, deepest first.
+    pub fn created_folders(&self) -> Result<Vec<PathBuf>, ExportError> {
+        let mut folders = self.list(STMT_SELECT_CREATED_FOLDERS)?;
+        folders.sort_by_key(|folder| std::cmp::Reverse(folder.components().count()));
+        Ok(folders)
+    }
+
+    pub fn forget_folder(&self, folder: &Path) -> Result<(), ExportError> {
+        self.forget(STMT_DELETE_CREATED_FOLDER, folder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A destination folder of its own for each test.
+    fn destination(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("{}-manifest-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn fingerprint(source: &str) -> ExportFingerprint {
+        ExportFingerprint::new(
+            source.to_string(),
+            "settings".to_string(),
+            "everything is copied".to_string(),
+        )
+    }
+
+    #[test]
+    fn records_up_to_date_files() {
+        let root = destination("record");
+        let file = root.join("Song.mp3");
+        std::fs::write(&file, b"audio").unwrap();
+        let manifest = ExportManifest::open(&root).unwrap();
+        assert!(!manifest.is_up_to_date(&file, &fingerprint("a")));
+
+        manifest.record(&file, &fingerprint("a"), None).unwrap();
+        assert!(manifest.is_up_to_date(&file, &fingerprint("a")));
+        assert_eq!(manifest.exported_files().unwrap(), vec![file.clone()]);
+        // Reopening reads the same entries back.
+        drop(manifest);
+        let manifest = ExportManifest::open_existing(&root).unwrap().unwrap();
+        assert!(manifest.is_up_to_date(&file, &fingerprint("a")));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn changes_make_files_stale() {
+        let root = destination("stale");
+        let file = root.join("Song.mp3");
+        std::fs::write(&file, b"audio").unwrap();
+        let manifest = ExportManifest::open(&root).unwrap();
+        manifest.record(&file, &fingerprint("a"), None).unwrap();
+
+        assert!(!manifest.is_up_to_date(&file, &fingerprint("b")));
+        let other_settings = ExportFingerprint::new(
+            "a".to_string(),
+            "other settings".to_string(),
+            "everything is copied".to_string(),
+        );
+        assert!(!manifest.is_up_to_date(&file, &other_settings));
+        std::fs::write(&file, b"longer audio").unwrap();
+        assert!(!manifest.is_up_to_date(&file, &fingerprint("a")));
+        std::fs::remove_file(&file).unwrap();
+        assert!(!manifest.is_up_to_date(&file, &fingerprint("a")));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn gain_tags_keep_files_up_to_date() {
+        let root = destination("gain");
+        let file = root.join("Song.mp3");
+        std::fs::write(&file, b"audio").unwrap();
+        let manifest = ExportManifest::open(&root).unwrap();
+        let loudness = Loudness::new(-12.0, -0.5, 180.0);
+        manifest
+            .record(&file, &fingerprint("a"), Some(&loudness))
+            .unwrap();
+        assert_eq!(manifest.loudness(&file), Some((loudness, String::new())));
+
+        std::fs::write(&file, b"audio with gain tags").unwrap();
+        manifest.record_gain_tags(&file, "tags").unwrap();
+        assert!(manifest.is_up_to_date(&file, &fingerprint("a")));
+        assert_eq!(
+            manifest.loudness(&file),
+            Some((loudness, "tags".to_string()))
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn migrates_earlier_manifests() {
+        let root = destination("migrate");
+        let file = root.join("Song.mp3");
+        std::fs::write(&file, b"audio").unwrap();
+        let connection = rusqlite::Connection::open(root.join(MANIFEST_FILE_NAME)).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE ExportedFiles (
+                    Path TEXT PRIMARY KEY NOT NULL,
+                    Source TEXT NOT NULL,
+                    Settings TEXT NOT NULL,
+                    Size INTEGER NOT NULL
+                );
+                INSERT INTO ExportedFiles VALUES ('Song.mp3', 'a', 'settings', 5);",
+            )
+            .unwrap();
+        drop(connection);
+
+        let manifest = ExportManifest::open(&root).unwrap();
+        assert!(manifest.is_up_to_date(&file, &fingerprint("a")));
+        assert_eq!(manifest.loudness(&file), None);
+        let loudness = Loudness::new(-12.0, -0.5, 180.0);
+        manifest
+            .record(&file, &fingerprint("a"), Some(&loudness))
+            .unwrap();
+        assert_eq!(manifest.loudness(&file), Some((loudness, String::new())));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,6 +1,13 @@
 use serde::Deserialize;
 
+use super::config::*;
+use super::error::*;
 use super::model2::*;
+use super::osudb::*;
+use super::realm::*;
+use super::sanitize::*;
+use super::script::*;
+use super::template::*;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::path::Path;
@@ -8,24 +15,67 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 pub trait OsuBeatmapSets {
-    fn beatmap_sets(&self) -> Vec<Box<dyn OsuBeatmapSet>>;
+    fn beatmap_sets(&self) -> Result<Vec<Box<dyn OsuBeatmapSet>>, ExportError>;
+    fn collections(&self) -> Result<Vec<OsuCollection>, ExportError>;
     fn boxed(self) -> Box<dyn OsuBeatmapSets>;
 }
 
 pub trait OsuBeatmapSet {
-    fn beatmaps(&self) -> Vec<OsuBeatmapInfoHolder>;
+    fn beatmaps(&self) -> Vec<Result<OsuBeatmapInfoHolder, ExportError>>;
     fn boxed(self) -> Box<dyn OsuBeatmapSet>;
 }
 
+/// Identifies a beatmap set by its online ID, or, for sets that were never
+/// submitted, by a hash of their folder name or content.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BeatmapSetId {
+    Local(String),
+    Online(u64),
+}
+
+impl BeatmapSetId {
+    /// Sets with the same ID are merged, so it keeps 64 bits of the hash.
+    pub fn local(seed: &str) -> Self {
+        Self::Local(format!("{:x}", md5::compute(seed.as_bytes()))[..16].to_string())
+    }
+    pub fn online_or_local(online_id: Option<i64>, seed: &str) -> Self {
+        match online_id.filter(|id| *id > 0) {
+            Some(id) => Self::Online(id as u64),
+            None => Self::local(seed),
+        }
+    }
+}
+
+impl std::fmt::Display for BeatmapSetId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Local(hash) => write!(f, "local-{}", hash),
+            Self::Online(id) => write!(f, "{}", id),
+        }
+    }
+}
+
 #[derive(Debug, Clone, new)]
 pub struct OsuBeatmapInfoHolder {
     pub ascii: BasicSongInfo,
     pub unicode: BasicSongInfo,
-    pub beatmapset_id: u64,
+    pub beatmapset_id: BeatmapSetId,
     pub background: Option<PathBuf>,
     pub audio: PathBuf,
     pub beatmap: PathBuf,
+    pub beatmap_md5: Option<String>,
     pub extensions: (Option<String>, Option<String>),
+    pub details: BeatmapDetails,
+}
+
+impl OsuBeatmapInfoHolder {
+    pub fn md5_hash(&self) -> Option<String> {
+        self.beatmap_md5.clone().or_else(|| {
+            read_source(&self.beatmap)
+                .ok()
+                .map(|content| format!("{:x}", md5::compute(content)))
+        })
+    }
 }
 
 #[derive(Debug, Clone, new)]
@@ -34,17 +84,42 @@ pub struct BasicSongInfoPair {
     pub unicode: BasicSongInfo,
 }
 
+impl BasicSongInfoPair {
+    /// Takes each field from the Unicode info when `unicode(field, value)`
+    /// says so, and from the romanised one otherwise.
+    pub fn select(&self, unicode: impl Fn(&str, &str) -> bool) -> BasicSongInfo {
+        let pick = |field: &str, ascii: &String, unicode_value: &String| {
+            if unicode(field, unicode_value) {
+                unicode_value.clone()
+            } else {
+                ascii.clone()
+            }
+        };
+        BasicSongInfo::new(
+            pick("title", &self.ascii.title, &self.unicode.title),
+            pick("artist", &self.ascii.artist, &self.unicode.artist),
+        )
+    }
+}
+
 #[derive(Debug, Clone, new)]
 pub struct OsuBeatmapInfoHolderSimple {
+    /// The title and artist for file names.
     pub info: BasicSongInfo,
+    /// The title and artist for tags and playlists.
+    pub tag_info: BasicSongInfo,
     pub info_pair: BasicSongInfoPair,
-    pub beatmapset_id: u64,
+    pub beatmapset_id: BeatmapSetId,
     pub background: Option<PathBuf>,
     pub audio: PathBuf,
     pub beatmap: PathBuf,
     pub extensions: (Option<String>, Option<String>),
+    pub details: BeatmapDetails,
+    /// The other text fields to keep in Unicode in file names.
+    pub unicode_fields: Vec<&'static str>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, new)]
 pub struct OsuBeatmapTrackInfo {
     pub info: OsuBeatmapInfoHolderSimple,
@@ -54,20 +129,36 @@ pub struct OsuBeatmapTrackInfo {
     pub audio_format: FFProbeAudioStreamCodec,
 }
 
-impl From<(OsuBeatmapInfoHolder, bool)> for OsuBeatmapInfoHolderSimple {
-    fn from((other, unicode_support): (OsuBeatmapInfoHolder, bool)) -> Self {
+impl From<(OsuBeatmapInfoHolder, &ScriptSelection)> for OsuBeatmapInfoHolderSimple {
+    fn from((other, script_selection): (OsuBeatmapInfoHolder, &ScriptSelection)) -> Self {
+        let ascii = other.ascii.romanised_or(&other.unicode);
+        let info_pair = BasicSongInfoPair::new(ascii, other.unicode);
+        let details = &other.details;
+        let unicode_fields = [
+            ("creator", &details.creator),
+            ("source", &details.source),
+            ("tags", &details.tags),
+            ("difficulty", &details.version),
+        ]
+        .into_iter()
+        .filter(|(field, value)| {
+            script_selection.filename_unicode(field, value.as_deref().unwrap_or_default())
+        })
+        .map(|(field, _)| field)
+        .collect();
         Self::new(
-            if unicode_support {
-                other.unicode.clone()
-            } else {
-                other.ascii.clone()
-            },
-            BasicSongInfoPair::new(other.ascii, other.unicode),
+            info_pair
+                .select(|field, value| script_selection.filename_unicode(field, value))
+                .or_set_id(&other.beatmapset_id),
+            info_pair.select(|field, value| script_selection.tag_unicode(field, value)),
+            info_pair,
             other.beatmapset_id,
             other.background,
             other.audio,
             other.beatmap,
             other.extensions,
+            other.details,
+            unicode_fields,
         )
     }
 }
@@ -78,6 +169,7 @@ pub struct OsuBeatmapInfoExtracted {
     pub unicode: BasicSongInfo,
     pub background: Option<String>,
     pub audio: String,
+    pub details: BeatmapDetails,
 }
 
 #[derive(Debug, Clone, new)]
@@ -86,19 +178,153 @@ pub struct BasicSongInfo {
     pub artist: String,
 }
 
+/// Everything else known about a difficulty, as far as its reader can tell.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BeatmapDetails {
+    pub creator: Option<String>,
+    pub source: Option<String>,
+    pub tags: Option<String>,
+    pub version: Option<String>,
+    pub beatmap_id: Option<i64>,
+    pub bpm: Option<f64>,
+    /// In seconds.
+    pub length: Option<f64>,
+    pub mode: Option<&'static str>,
+    pub ranked_status: Option<&'static str>,
+    /// As a Unix timestamp.
+    pub date_added: Option<i64>,
+}
+
+impl BeatmapDetails {
+    fn parse(beatmap_string: &str) -> Self {
+        let beatmap_sections = get_osu_beatmap_sections(beatmap_string);
+        let section_values = |section: &str, separator: char| -> HashMap<String, String> {
+            beatmap_sections
+                .get(section)
+                .map(|lines| {
+                    lines
+                        .iter()
+                        .filter_map(|line| line.split_once(separator))
+                        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let general = section_values("general", ':');
+        let metadata = section_values("metadata", ':');
+        let non_empty = |key: &str| metadata.get(key).filter(|value| !value.is_empty()).cloned();
+        let bpm = beatmap_sections.get("timingpoints").and_then(|lines| {
+            lines.iter().find_map(|line| {
+                let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+                let beat_length: f64 = fields.get(1)?.parse().ok()?;
+                let uninherited = fields.get(6).map(|x| *x != "0").unwrap_or(true);
+                if uninherited && beat_length > 0.0 {
+                    Some(60000.0 / beat_length)
+                } else {
+                    None
+                }
+            })
+        });
+        let length = beatmap_sections.get("hitobjects").and_then(|lines| {
+            lines
+                .iter()
+                .filter_map(|line| line.split(',').nth(2)?.trim().parse::<f64>().ok())
+                .reduce(f64::max)
+                .map(|time| time / 1000.0)
+        });
+        Self {
+            creator: non_empty("Creator"),
+            source: non_empty("Source"),
+            tags: non_empty("Tags"),
+            version: non_empty("Version"),
+            beatmap_id: non_empty("BeatmapID")
+                .and_then(|id| id.parse().ok())
+                .filter(|id| *id > 0),
+            bpm,
+            length,
+            mode: general
+                .get("Mode")
+                .and_then(|mode| mode.parse::<u8>().ok())
+                .and_then(|mode| OsuDbGameMode::try_from(mode).ok())
+                .map(|mode| mode.name()),
+            ranked_status: None,
+            date_added: None,
+        }
+    }
+}
+
+/// Names osu!lazer's `BeatmapOnlineStatus`.
+pub fn lazer_ranked_status_name(status: i64) -> Option<&'static str> {
+    match status {
+        -4 => Some("modified"),
+        -2 => Some("graveyard"),
+        -1 => Some("wip"),
+        0 => Some("pending"),
+        1 => Some("ranked"),
+        2 => Some("approved"),
+        3 => Some("qualified"),
+        4 => Some("loved"),
+        _ => None,
+    }
+}
+
+/// When a file or folder was last modified, standing in for when a set was
+/// added where osu! doesn't record it.
+pub fn modification_date(path: &Path) -> Option<i64> {
+    path.metadata()
+        .ok()?
+        .modified()
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()
+        .map(|date| date.as_secs() as i64)
+}
+
+/// Spells text in ASCII: accents are dropped, kana, Cyrillic and Greek are
+/// romanised and Han characters are written in pinyin.
+pub fn transliterate(text: &str) -> String {
+    deunicode::deunicode_with_tofu(text, "")
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
 impl BasicSongInfo {
-    pub fn filter_ascii(&self) -> Self {
+    pub fn transliterate(&self) -> Self {
+        Self::new(transliterate(&self.title), transliterate(&self.artist))
+    }
+    /// This info in ASCII, where each field left empty falls back to the
+    /// same field of `fallback`.
+    pub fn romanised_or(&self, fallback: &BasicSongInfo) -> Self {
+        let romanised = |field: &str, fallback_field: &str| {
+            Some(transliterate(field))
+                .filter(|field| !field.is_empty())
+                .unwrap_or_else(|| transliterate(fallback_field))
+        };
         Self::new(
-            self.title.chars().filter(char::is_ascii).collect(),
-            self.artist.chars().filter(char::is_ascii).collect(),
+            romanised(&self.title, &fallback.title),
+            romanised(&self.artist, &fallback.artist),
         )
     }
+    /// This info with the set ID in the fields left empty, so that file
+    /// names are never made of separators alone.
+    pub fn or_set_id(self, beatmapset_id: &BeatmapSetId) -> Self {
+        let or_set_id = |field: String| {
+            if field.is_empty() {
+                beatmapset_id.to_string()
+            } else {
+                field
+            }
+        };
+        Self::new(or_set_id(self.title), or_set_id(self.artist))
+    }
 }
 
 impl TryFrom<&PathBuf> for OsuBeatmapInfoExtracted {
-    type Error = String;
-    fn try_from(path: &PathBuf) -> Result<Self, String> {
-        Self::try_from(&std::fs::read_to_string(path).map_err(|e| format!("{:?}", e))?)
+    type Error = ExportError;
+    fn try_from(path: &PathBuf) -> Result<Self, ExportError> {
+        let bytes = std::fs::read(path).map_err(ExportError::io(path))?;
+        Self::try_from(&String::from_utf8_lossy(&bytes).into_owned()).map_err(|e| e.at(path))
     }
 }
 
@@ -141,23 +367,24 @@ fn get_osu_beatmap_sections(beatmap_string: &str) -> HashMap<String, Vec<String>
                 .filter(|line| !line.is_empty())
                 .collect::<Vec<&&str>>()
         })
-        .map(|vec| {
-            let section_syntax = vec.first().unwrap().trim().to_string();
+        .filter_map(|vec| {
+            let section_syntax = vec.first()?.trim().to_string();
             let section_key = section_syntax[1..(section_syntax.len() - 1)]
                 .trim()
                 .to_string();
-            (
+            Some((
                 section_key.to_lowercase(),
                 vec.iter().skip(1).map(|s| s.to_string()).collect(),
-            )
+            ))
         })
         .collect();
     beatmap_sections
 }
 
 impl TryFrom<&String> for OsuBeatmapInfoExtracted {
-    type Error = String;
-    fn try_from(beatmap_string: &String) -> Result<Self, String> {
+    type Error = ExportError;
+    fn try_from(beatmap_string: &String) -> Result<Self, ExportError> {
+        let missing = |what: &str| ExportError::parse(Path::new(""), format!("missing {}", what));
         let beatmap_sections: HashMap<String, Vec<String>> =
             get_osu_beatmap_sections(beatmap_string);
         // println!("{:#?}", beatmap_sections);
@@ -168,41 +395,30 @@ impl TryFrom<&String> for OsuBeatmapInfoExtracted {
                 events_vec
                     .iter()
                     .filter(|line| line.starts_with("0,0,\""))
-                    .map(|line| line.split('"').nth(1).unwrap())
-                    .next()
+                    .find_map(|line| line.split('"').nth(1))
             })
             .map(|item| item.to_string());
         let general: HashMap<String, String> = beatmap_sections
             .get("general")
-            .unwrap()
+            .ok_or_else(|| missing("[General] section"))?
             .iter()
-            .filter(|line| line.contains(": "))
-            .map(|line| {
-                let mut spl = line.splitn(2, ": ");
-                (
-                    spl.next().unwrap().to_string(),
-                    spl.next().unwrap().to_string(),
-                )
-            })
+            .filter_map(|line| line.split_once(": "))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
             .collect();
         // println!("{:#?}", general);
-        let audio_filename = general.get("AudioFilename").unwrap();
+        let audio_filename = general
+            .get("AudioFilename")
+            .ok_or_else(|| missing("AudioFilename"))?;
         let metadata: HashMap<String, String> = beatmap_sections
             .get("metadata")
-            .unwrap()
+            .ok_or_else(|| missing("[Metadata] section"))?
             .iter()
-            .filter(|line| line.contains(':'))
-            .map(|line| {
-                let mut spl = line.splitn(2, ':');
-                (
-                    spl.next().unwrap().to_string(),
-                    spl.next().unwrap().to_string(),
-                )
-            })
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
             .collect();
         // println!("{:#?}", metadata);
-        let title = metadata.get("Title").unwrap();
-        let artist = metadata.get("Artist").unwrap();
+        let title = metadata.get("Title").ok_or_else(|| missing("Title"))?;
+        let artist = metadata.get("Artist").ok_or_else(|| missing("Artist"))?;
         let title_unicode_opt = metadata.get("TitleUnicode");
         let artist_unicode_opt = metadata.get("ArtistUnicode");
         let info_unknown = BasicSongInfo::new(title.to_string(), artist.to_string());
@@ -228,17 +444,20 @@ impl TryFrom<&String> for OsuBeatmapInfoExtracted {
             info_unicode,
             background,
             audio_filename.to_string(),
+            BeatmapDetails::parse(beatmap_string),
         ))
     }
 }
 
 #[derive(Debug, Clone, new)]
 pub struct Osu40BeatmapSetsReader {
+    pub osu_folder: PathBuf,
     pub beatmapsets_folder: PathBuf,
 }
 
 #[derive(Debug, Clone, new)]
 pub struct Osu50BeatmapSetsReader {
+    pub osu_folder: PathBuf,
     pub hash_resolver: Arc<Osu50HashResolver>,
     pub connection: Arc<rusqlite::Connection>,
 }
@@ -249,7 +468,7 @@ pub struct Osu50HashResolver {
 }
 
 impl Osu50HashResolver {
-    pub fn resolve(&self, hash: &str) -> Result<PathBuf, String> {
+    pub fn resolve(&self, hash: &str) -> Result<PathBuf, ExportError> {
         let final_path_buf = self
             .folder
             .join(hash.chars().take(1).collect::<String>())
@@ -258,57 +477,71 @@ impl Osu50HashResolver {
         if final_path_buf.is_file() {
             Ok(final_path_buf)
         } else {
-            Err(format!("{:?} is not a hashed file", final_path_buf))
+            Err(ExportError::not_found(&final_path_buf))
         }
     }
 }
 
 impl TryFrom<&PathBuf> for Osu40BeatmapSetsReader {
-    type Error = String;
-    fn try_from(path: &PathBuf) -> Result<Self, String> {
+    type Error = ExportError;
+    fn try_from(path: &PathBuf) -> Result<Self, ExportError> {
         if !path.is_dir() {
-            return Err(format!("{:?} is not a directory", path));
+            return Err(ExportError::Input(format!("{:?} is not a directory", path)));
         }
-        let songs_path = path.join("Songs");
+        let songs_path = stable_beatmap_directory(path).unwrap_or_else(|| path.join("Songs"));
         if !songs_path.is_dir() {
-            return Err(format!(
+            return Err(ExportError::Input(format!(
                 "{:?} directory was not found in your osu!classic directory",
                 songs_path
-            ));
+            )));
         }
-        Ok(Self::new(songs_path))
+        Ok(Self::new(path.clone(), songs_path))
     }
 }
 
+fn read_collection_db(osu_folder: &Path) -> Result<Vec<OsuCollection>, ExportError> {
+    let collection_db_path = osu_folder.join("collection.db");
+    if !collection_db_path.is_file() {
+        return Ok(vec![]);
+    }
+    let data = std::fs::read(&collection_db_path).map_err(ExportError::io(&collection_db_path))?;
+    Ok(OsuCollectionDb::try_from(&data[..])
+        .map_err(|e| ExportError::parse(&collection_db_path, e))?
+        .collections)
+}
+
+/// Sets are named after their online ID, like `123 Artist - Title`; the whole
+/// name seeds the ID of the ones that aren't.
+fn beatmapset_id_from_name(path: &Path) -> BeatmapSetId {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let online_id = name.split(' ').next().and_then(|id| id.parse::<i64>().ok());
+    BeatmapSetId::online_or_local(online_id, &name)
+}
+
 impl OsuBeatmapSets for Osu40BeatmapSetsReader {
     fn boxed(self) -> Box<dyn OsuBeatmapSets> {
         Box::new(self)
     }
-    fn beatmap_sets(&self) -> Vec<Box<dyn OsuBeatmapSet>> {
+    fn collections(&self) -> Result<Vec<OsuCollection>, ExportError> {
+        read_collection_db(&self.osu_folder)
+    }
+    fn beatmap_sets(&self) -> Result<Vec<Box<dyn OsuBeatmapSet>>, ExportError> {
         let folders: Vec<PathBuf> = self
             .beatmapsets_folder
             .read_dir()
-            .unwrap()
-            .map(|entry_opt| entry_opt.unwrap())
+            .map_err(ExportError::io(&self.beatmapsets_folder))?
+            .filter_map(|entry_opt| entry_opt.ok())
             .map(|entry| entry.path())
             .filter(|path| path.is_dir())
-            .filter(|path| {
-                path.file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .split(' ')
-                    .next()
-                    .unwrap()
-                    .parse::<u64>()
-                    .is_ok()
-            })
             .collect();
-        folders
+        Ok(folders
             .iter()
             .map(|folder| Osu40BeatmapSet::new(folder.clone()))
             .map(|boxable| boxable.boxed())
-            .collect()
+            .collect())
     }
 }
 
@@ -321,123 +554,452 @@ impl OsuBeatmapSet for Osu40BeatmapSet {
     fn boxed(self) -> Box<dyn OsuBeatmapSet> {
         Box::new(self)
     }
-    fn beatmaps(&self) -> Vec<OsuBeatmapInfoHolder> {
-        let beatmapset_id: u64 = self
-            .beatmap_folder
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .split(' ')
-            .next()
-            .unwrap()
-            .parse::<u64>()
-            .unwrap();
-        let osu_files: Vec<PathBuf> = self
-            .beatmap_folder
+    fn beatmaps(&self) -> Vec<Result<OsuBeatmapInfoHolder, ExportError>> {
+        let beatmapset_id = beatmapset_id_from_name(&self.beatmap_folder);
+        let osu_files: Vec<PathBuf> = match self.beatmap_folder.read_dir() {
+            Ok(entries) => entries
+                .filter_map(|entry_opt| entry_opt.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file())
+                .filter(|path| path.extension().map(|ext| ext == "osu").unwrap_or(false))
+                .collect(),
+            Err(e) => return vec![Err(ExportError::io(&self.beatmap_folder)(e))],
+        };
+        osu_files
+            .iter()
+            .map(|path| {
+                let beatmap_info = OsuBeatmapInfoExtracted::try_from(path)?;
+                let background = beatmap_info.background.clone().and_then(|bkg| {
+                    let bkg_test = self.beatmap_folder.join(bkg);
+                    if bkg_test.is_file() {
+                        Some(bkg_test)
+                    } else {
+                        None
+                    }
+                });
+                let audio = self.beatmap_folder.join(&beatmap_info.audio);
+                if !audio.is_file() {
+                    return Err(ExportError::not_found(&audio));
+                }
+                let extensions = (
+                    audio
+                        .extension()
+                        .and_then(|x| x.to_str().map(|y| y.to_lowercase())),
+                    background.as_ref().and_then(|bkg| {
+                        bkg.extension()
+                            .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
+                    }),
+                );
+                Ok(OsuBeatmapInfoHolder::new(
+                    beatmap_info
+                        .ascii_opt
+                        .clone()
+                        .unwrap_or_else(|| beatmap_info.unicode.transliterate()),
+                    beatmap_info.unicode.clone(),
+                    beatmapset_id.clone(),
+                    background,
+                    audio,
+                    path.clone(),
+                    None,
+                    extensions,
+                    BeatmapDetails {
+                        date_added: modification_date(&self.beatmap_folder),
+                        ..beatmap_info.details.clone()
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct OsuArchiveBeatmapSetsReader {
+    pub archives_folder: PathBuf,
+}
+
+fn is_beatmap_archive(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case("osz") || ext.eq_ignore_ascii_case("olz"))
+            .unwrap_or(false)
+}
+
+impl TryFrom<&PathBuf> for OsuArchiveBeatmapSetsReader {
+    type Error = ExportError;
+    fn try_from(path: &PathBuf) -> Result<Self, ExportError> {
+        if !path.is_dir() {
+            return Err(ExportError::Input(format!("{:?} is not a directory", path)));
+        }
+        let has_archives = path
+            .read_dir()
+            .map_err(ExportError::io(path))?
+            .filter_map(|entry| entry.ok())
+            .any(|entry| is_beatmap_archive(&entry.path()));
+        if !has_archives {
+            return Err(ExportError::Input(format!(
+                "{:?} directory has no .osz or .olz beatmap archives",
+                path
+            )));
+        }
+        Ok(Self::new(path.clone()))
+    }
+}
+
+impl OsuBeatmapSets for OsuArchiveBeatmapSetsReader {
+    fn boxed(self) -> Box<dyn OsuBeatmapSets> {
+        Box::new(self)
+    }
+    fn collections(&self) -> Result<Vec<OsuCollection>, ExportError> {
+        Ok(vec![])
+    }
+    fn beatmap_sets(&self) -> Result<Vec<Box<dyn OsuBeatmapSet>>, ExportError> {
+        let mut archives: Vec<PathBuf> = self
+            .archives_folder
             .read_dir()
-            .unwrap()
-            .map(|entry_opt| entry_opt.unwrap())
+            .map_err(ExportError::io(&self.archives_folder))?
+            .filter_map(|entry| entry.ok())
             .map(|entry| entry.path())
-            .filter(|path| path.is_file())
-            .filter(|path| {
-                path.extension()
-                    .map(|ext| ext.to_str().unwrap() == "osu")
-                    .unwrap_or(false)
-            })
+            .filter(|path| is_beatmap_archive(path))
             .collect();
-        let osu_infos_extracted: Vec<(&PathBuf, OsuBeatmapInfoExtracted)> = osu_files
+        archives.sort();
+        Ok(archives
+            .into_iter()
+            .map(|archive| OsuArchiveBeatmapSet::new(archive).boxed())
+            .collect())
+    }
+}
+
+/// The files of archived sets are never extracted: they are known as
+/// `<archive>/<entry>`, and read from the archive when needed.
+#[derive(Debug, Clone, new)]
+pub struct OsuArchiveBeatmapSet {
+    pub archive: PathBuf,
+}
+
+/// The archive and entry name of a file inside a beatmap archive.
+pub fn archive_entry(path: &Path) -> Option<(&Path, String)> {
+    let archive = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| is_beatmap_archive(ancestor))?;
+    let entry_name = path
+        .strip_prefix(archive)
+        .ok()?
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some((archive, entry_name))
+}
+
+fn open_archive_entry<T>(
+    path: &Path,
+    archive: &Path,
+    entry_name: &str,
+    with_entry: impl FnOnce(zip::read::ZipFile<'_, std::fs::File>) -> Result<T, ExportError>,
+) -> Result<T, ExportError> {
+    let file = std::fs::File::open(archive).map_err(ExportError::io(archive))?;
+    let mut zip_archive = zip::ZipArchive::new(file).map_err(|e| ExportError::parse(archive, e))?;
+    // Some archivers write Windows separators.
+    let index = (0..zip_archive.len())
+        .find(|index| {
+            zip_archive
+                .name_for_index(*index)
+                .map(|name| name.replace('\\', "/") == entry_name)
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| ExportError::not_found(path))?;
+    let entry = zip_archive
+        .by_index(index)
+        .map_err(|e| ExportError::parse(path, e))?;
+    with_entry(entry)
+}
+
+/// Reads a source file, whether it is in a folder or in a beatmap archive.
+pub fn read_source(path: &Path) -> Result<Vec<u8>, ExportError> {
+    match archive_entry(path) {
+        Some((archive, entry_name)) => {
+            open_archive_entry(path, archive, &entry_name, |mut entry| {
+                let mut content = Vec::with_capacity(entry.size() as usize);
+                std::io::Read::read_to_end(&mut entry, &mut content)
+                    .map_err(ExportError::io(path))?;
+                Ok(content)
+            })
+        }
+        None => std::fs::read(path).map_err(ExportError::io(path)),
+    }
+}
+
+/// The size of a source file, whether it is in a folder or in a beatmap
+/// archive.
+pub fn source_len(path: &Path) -> Option<u64> {
+    match archive_entry(path) {
+        Some((archive, entry_name)) => {
+            open_archive_entry(path, archive, &entry_name, |entry| Ok(entry.size())).ok()
+        }
+        None => path.metadata().ok().map(|metadata| metadata.len()),
+    }
+}
+
+impl OsuBeatmapSet for OsuArchiveBeatmapSet {
+    fn boxed(self) -> Box<dyn OsuBeatmapSet> {
+        Box::new(self)
+    }
+    fn beatmaps(&self) -> Vec<Result<OsuBeatmapInfoHolder, ExportError>> {
+        let beatmapset_id = beatmapset_id_from_name(&self.archive.with_extension(""));
+        let zip_archive_result = std::fs::File::open(&self.archive)
+            .map_err(ExportError::io(&self.archive))
+            .and_then(|file| {
+                zip::ZipArchive::new(file).map_err(|e| ExportError::parse(&self.archive, e))
+            });
+        let mut zip_archive = match zip_archive_result {
+            Ok(zip_archive) => zip_archive,
+            Err(e) => return vec![Err(e)],
+        };
+        let entry_names: Vec<String> = zip_archive.file_names().map(|x| x.to_string()).collect();
+        let find_entry = |wanted: &str| {
+            let wanted_normalized = wanted.replace('\\', "/").to_lowercase();
+            entry_names
+                .iter()
+                .find(|entry_name| entry_name.to_lowercase() == wanted_normalized)
+                .cloned()
+                .ok_or_else(|| ExportError::not_found(&self.archive.join(wanted)))
+        };
+        let osu_entries: Vec<String> = entry_names
             .iter()
-            .map(|path| (path, OsuBeatmapInfoExtracted::try_from(path).unwrap()))
+            .filter(|entry_name| entry_name.to_lowercase().ends_with(".osu"))
+            .cloned()
             .collect();
-        osu_infos_extracted
+        osu_entries
+            .iter()
+            .map(|osu_entry| {
+                let osu_entry_path = self.archive.join(osu_entry);
+                let mut osu_bytes = vec![];
+                zip_archive
+                    .by_name(osu_entry)
+                    .map_err(|e| ExportError::parse(&osu_entry_path, e))
+                    .and_then(|mut entry| {
+                        std::io::Read::read_to_end(&mut entry, &mut osu_bytes)
+                            .map_err(ExportError::io(&osu_entry_path))
+                    })?;
+                let osu_content = String::from_utf8_lossy(&osu_bytes).into_owned();
+                let beatmap_info = OsuBeatmapInfoExtracted::try_from(&osu_content)
+                    .map_err(|e| e.at(&osu_entry_path))?;
+                let entry_path =
+                    |entry_name: &str| self.archive.join(entry_name.replace('\\', "/"));
+                let audio = entry_path(&find_entry(&beatmap_info.audio)?);
+                let background = beatmap_info
+                    .background
+                    .as_ref()
+                    .and_then(|bkg| find_entry(bkg).ok())
+                    .map(|entry_name| entry_path(&entry_name));
+                let beatmap = entry_path(osu_entry);
+                let extension_of = |path: &Path| {
+                    path.extension()
+                        .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
+                };
+                let extensions = (
+                    extension_of(&audio),
+                    background.as_deref().and_then(extension_of),
+                );
+                Ok(OsuBeatmapInfoHolder::new(
+                    beatmap_info
+                        .ascii_opt
+                        .clone()
+                        .unwrap_or_else(|| beatmap_info.unicode.transliterate()),
+                    beatmap_info.unicode.clone(),
+                    beatmapset_id.clone(),
+                    background,
+                    audio,
+                    beatmap,
+                    Some(format!("{:x}", md5::compute(&osu_bytes))),
+                    extensions,
+                    BeatmapDetails {
+                        date_added: modification_date(&self.archive),
+                        ..beatmap_info.details.clone()
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct Osu40DbBeatmapSetsReader {
+    pub osu_folder: PathBuf,
+    pub beatmapsets_folder: PathBuf,
+    pub osu_db: Arc<OsuDb>,
+}
+
+impl TryFrom<&PathBuf> for Osu40DbBeatmapSetsReader {
+    type Error = ExportError;
+    fn try_from(path: &PathBuf) -> Result<Self, ExportError> {
+        let songs_path = Osu40BeatmapSetsReader::try_from(path)?.beatmapsets_folder;
+        let osu_db_path = path.join("osu!.db");
+        if !osu_db_path.is_file() {
+            return Err(ExportError::Input(format!(
+                "{:?} file was not found in your osu!classic directory",
+                osu_db_path
+            )));
+        }
+        let data = std::fs::read(&osu_db_path).map_err(ExportError::io(&osu_db_path))?;
+        let osu_db =
+            OsuDb::try_from(&data[..]).map_err(|e| ExportError::database(&osu_db_path, e))?;
+        Ok(Self::new(path.clone(), songs_path, Arc::new(osu_db)))
+    }
+}
+
+impl OsuBeatmapSets for Osu40DbBeatmapSetsReader {
+    fn boxed(self) -> Box<dyn OsuBeatmapSets> {
+        Box::new(self)
+    }
+    fn collections(&self) -> Result<Vec<OsuCollection>, ExportError> {
+        read_collection_db(&self.osu_folder)
+    }
+    fn beatmap_sets(&self) -> Result<Vec<Box<dyn OsuBeatmapSet>>, ExportError> {
+        let mut folder_names: Vec<&String> = vec![];
+        let mut beatmaps_by_folder: HashMap<&String, Vec<OsuDbBeatmap>> = HashMap::new();
+        for beatmap in self.osu_db.beatmaps.iter() {
+            beatmaps_by_folder
+                .entry(&beatmap.folder_name)
+                .or_insert_with(|| {
+                    folder_names.push(&beatmap.folder_name);
+                    vec![]
+                })
+                .push(beatmap.clone());
+        }
+        Ok(folder_names
+            .into_iter()
+            .filter_map(|folder_name| {
+                let beatmaps = beatmaps_by_folder.remove(folder_name)?;
+                Some(
+                    Osu40DbBeatmapSet::new(self.beatmapsets_folder.join(folder_name), beatmaps)
+                        .boxed(),
+                )
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct Osu40DbBeatmapSet {
+    pub beatmap_folder: PathBuf,
+    pub beatmaps: Vec<OsuDbBeatmap>,
+}
+
+impl OsuBeatmapSet for Osu40DbBeatmapSet {
+    fn boxed(self) -> Box<dyn OsuBeatmapSet> {
+        Box::new(self)
+    }
+    fn beatmaps(&self) -> Vec<Result<OsuBeatmapInfoHolder, ExportError>> {
+        // osu!.db doesn't know about backgrounds, so a single .osu file per set is
+        // parsed for it instead of every difficulty.
+        let background: Option<PathBuf> = self
+            .beatmaps
+            .iter()
+            .map(|beatmap| self.beatmap_folder.join(&beatmap.osu_file))
+            .filter(|path| path.is_file())
+            .find_map(|path| OsuBeatmapInfoExtracted::try_from(&path).ok())
+            .and_then(|beatmap_info| beatmap_info.background)
+            .map(|bkg| self.beatmap_folder.join(bkg))
+            .filter(|bkg| bkg.is_file());
+        let background_extension = background.as_ref().and_then(|bkg| {
+            bkg.extension()
+                .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
+        });
+        self.beatmaps
             .iter()
-            .filter_map(
-                |(path, beatmap_info): &(&PathBuf, OsuBeatmapInfoExtracted)| {
-                    let background = beatmap_info.background.clone().and_then(|bkg| {
-                        let bkg_test = self.beatmap_folder.join(bkg);
-                        if bkg_test.is_file() {
-                            Some(bkg_test)
-                        } else {
-                            None
-                        }
-                    });
-                    let audio_opt = {
-                        let aud = beatmap_info.audio.clone();
-                        let aud_test = self.beatmap_folder.join(aud);
-                        if aud_test.is_file() {
-                            Some(aud_test)
-                        } else {
-                            None
-                        }
+            .filter(|beatmap| !beatmap.audio_file.is_empty())
+            .map(|beatmap| {
+                let audio = self.beatmap_folder.join(&beatmap.audio_file);
+                if !audio.is_file() {
+                    return Err(ExportError::not_found(&audio));
+                }
+                let info_unknown =
+                    BasicSongInfo::new(beatmap.title.clone(), beatmap.artist.clone());
+                let (info_ascii, info_unicode) =
+                    if beatmap.title_unicode.is_empty() || beatmap.artist_unicode.is_empty() {
+                        (info_unknown.transliterate(), info_unknown)
+                    } else {
+                        (
+                            info_unknown,
+                            BasicSongInfo::new(
+                                beatmap.title_unicode.clone(),
+                                beatmap.artist_unicode.clone(),
+                            ),
+                        )
                     };
-                    let extensions = (
-                        audio_opt.as_ref().and_then(|audio| {
-                            PathBuf::from(&audio)
-                                .extension()
-                                .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
-                        }),
-                        background.as_ref().and_then(|bkg| {
-                            PathBuf::from(&bkg)
-                                .extension()
-                                .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
-                        }),
-                    );
-                    audio_opt.and_then(|audio| {
-                        if audio.is_file() {
-                            Some(OsuBeatmapInfoHolder::new(
-                                beatmap_info
-                                    .ascii_opt
-                                    .clone()
-                                    .unwrap_or_else(|| beatmap_info.unicode.filter_ascii()),
-                                beatmap_info.unicode.clone(),
-                                beatmapset_id,
-                                background,
-                                audio,
-                                PathBuf::from(path),
-                                extensions,
-                            ))
-                        } else {
-                            None
-                        }
-                    })
-                },
-            )
+                let audio_extension = audio
+                    .extension()
+                    .and_then(|x| x.to_str().map(|y| y.to_lowercase()));
+                Ok(OsuBeatmapInfoHolder::new(
+                    info_ascii,
+                    info_unicode,
+                    BeatmapSetId::online_or_local(
+                        Some(beatmap.beatmapset_id as i64),
+                        &beatmap.folder_name,
+                    ),
+                    background.clone(),
+                    audio,
+                    self.beatmap_folder.join(&beatmap.osu_file),
+                    Some(beatmap.md5_hash.clone()),
+                    (audio_extension, background_extension.clone()),
+                    BeatmapDetails {
+                        creator: Some(beatmap.creator.clone()).filter(|x| !x.is_empty()),
+                        source: Some(beatmap.source.clone()).filter(|x| !x.is_empty()),
+                        tags: Some(beatmap.tags.clone()).filter(|x| !x.is_empty()),
+                        version: Some(beatmap.difficulty.clone()).filter(|x| !x.is_empty()),
+                        beatmap_id: Some(beatmap.beatmap_id as i64).filter(|id| *id > 0),
+                        bpm: beatmap.bpm,
+                        length: Some(beatmap.total_time as f64 / 1000.0),
+                        mode: Some(beatmap.game_mode.name()),
+                        ranked_status: beatmap.ranked_status.name(),
+                        date_added: modification_date(&self.beatmap_folder),
+                    },
+                ))
+            })
             .collect()
     }
 }
 
 impl TryFrom<&PathBuf> for Osu50BeatmapSetsReader {
-    type Error = String;
-    fn try_from(path: &PathBuf) -> Result<Self, String> {
+    type Error = ExportError;
+    fn try_from(path: &PathBuf) -> Result<Self, ExportError> {
         if !path.is_dir() {
-            return Err(format!("{:?} is not a directory", path));
+            return Err(ExportError::Input(format!("{:?} is not a directory", path)));
         }
+        let path = &lazer_storage_folder(path);
         let files_path = path.join("files");
         if !files_path.is_dir() {
-            return Err(format!(
+            return Err(ExportError::Input(format!(
                 "{:?} directory was not found in your osu!lazer directory",
                 files_path
-            ));
+            )));
         }
         let client_path = path.join("client.db");
         if !client_path.is_file() {
-            return Err(format!(
+            return Err(ExportError::Input(format!(
                 "{:?} file was not found in your osu!lazer directory",
-                files_path
-            ));
+                client_path
+            )));
         }
         let connection = rusqlite::Connection::open_with_flags(
-            client_path,
+            &client_path,
             rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )
-        .map_err(|x| format!("{:?}", x))?;
-        let mut connection_memory = rusqlite::Connection::open_in_memory().unwrap();
+        .map_err(|x| ExportError::database(&client_path, x))?;
+        let mut connection_memory = rusqlite::Connection::open_in_memory()
+            .map_err(|x| ExportError::database(&client_path, x))?;
         rusqlite::backup::Backup::new(&connection, &mut connection_memory)
-            .unwrap()
-            .run_to_completion(100000, std::time::Duration::from_millis(0), None)
-            .unwrap();
+            .and_then(|backup| {
+                backup.run_to_completion(100000, std::time::Duration::from_millis(0), None)
+            })
+            .map_err(|x| ExportError::database(&client_path, x))?;
+        #[allow(clippy::arc_with_non_send_sync)]
         Ok(Self::new(
+            path.clone(),
             Arc::new(Osu50HashResolver::new(files_path)),
             Arc::new(connection_memory),
         ))
@@ -448,11 +1010,15 @@ impl OsuBeatmapSets for Osu50BeatmapSetsReader {
     fn boxed(self) -> Box<dyn OsuBeatmapSets> {
         Box::new(self)
     }
-    fn beatmap_sets(&self) -> Vec<Box<dyn OsuBeatmapSet>> {
+    fn collections(&self) -> Result<Vec<OsuCollection>, ExportError> {
+        read_collection_db(&self.osu_folder)
+    }
+    fn beatmap_sets(&self) -> Result<Vec<Box<dyn OsuBeatmapSet>>, ExportError> {
+        let client_path = self.osu_folder.join("client.db");
         let mut stmt = self
             .connection
             .prepare(PRP_STMT_OSU_LAZER_LIST_BEATMAPSETS)
-            .unwrap();
+            .map_err(|x| ExportError::database(&client_path, x))?;
         let beatmap_set_db_listing_item: Vec<Osu50BeatmapSetDbListingItem> = stmt
             .query_map(rusqlite::params![], |row| {
                 Ok(Osu50BeatmapSetDbListingItem::new(
@@ -461,23 +1027,26 @@ impl OsuBeatmapSets for Osu50BeatmapSetsReader {
                     row.get(2)?,
                     row.get(3)?,
                     row.get(4)?,
-                    (row.get(5)?, row.get(6)?),
+                    row.get(5)?,
+                    row.get(6)?,
                     (row.get(7)?, row.get(8)?),
+                    (row.get(9)?, row.get(10)?),
                 ))
             })
-            .unwrap()
+            .map_err(|x| ExportError::database(&client_path, x))?
             .filter_map(|x| x.ok())
             .collect();
-        beatmap_set_db_listing_item
+        Ok(beatmap_set_db_listing_item
             .into_iter()
             .map(|beatmapset_db_info| {
                 Box::new(Osu50BeatmapSet::new(
                     self.hash_resolver.clone(),
                     self.connection.clone(),
+                    client_path.clone(),
                     beatmapset_db_info,
                 )) as Box<dyn OsuBeatmapSet>
             })
-            .collect()
+            .collect())
     }
 }
 
@@ -485,6 +1054,7 @@ impl OsuBeatmapSets for Osu50BeatmapSetsReader {
 pub struct Osu50BeatmapSet {
     pub hash_resolver: Arc<Osu50HashResolver>,
     pub connection: Arc<rusqlite::Connection>,
+    pub database_path: PathBuf,
     pub beatmapset_db_info: Osu50BeatmapSetDbListingItem,
 }
 
@@ -492,127 +1062,532 @@ impl OsuBeatmapSet for Osu50BeatmapSet {
     fn boxed(self) -> Box<dyn OsuBeatmapSet> {
         Box::new(self)
     }
-    fn beatmaps(&self) -> Vec<OsuBeatmapInfoHolder> {
-        if let Some(audio) = self
+    fn beatmaps(&self) -> Vec<Result<OsuBeatmapInfoHolder, ExportError>> {
+        let background = self
             .beatmapset_db_info
-            .audio
+            .background
             .1
             .clone()
-            .and_then(|hash| self.hash_resolver.resolve(&hash).ok())
-        {
-            let background = self
-                .beatmapset_db_info
-                .background
-                .1
-                .clone()
-                .and_then(|hash| self.hash_resolver.resolve(&hash).ok());
-            let beatmapset_id = self.beatmapset_db_info.id;
-            let title = self.beatmapset_db_info.title.clone();
-            let artist = self.beatmapset_db_info.artist.clone();
-            let title_unicode_opt = self.beatmapset_db_info.title_unicode.clone();
-            let artist_unicode_opt = self.beatmapset_db_info.artist_unicode.clone();
+            .and_then(|hash| self.hash_resolver.resolve(&hash).ok());
+        let beatmapset_key = self.beatmapset_db_info.id;
+        let beatmapset_id = BeatmapSetId::online_or_local(
+            self.beatmapset_db_info.online_id,
+            &self.beatmapset_db_info.hash.clone().unwrap_or_default(),
+        );
+        let song_infos = |title: String,
+                          artist: String,
+                          title_unicode_opt: Option<String>,
+                          artist_unicode_opt: Option<String>| {
             let info_unknown = BasicSongInfo::new(title, artist);
             let info_unicode_opt = title_unicode_opt.and_then(|title_unicode| {
-                artist_unicode_opt.map(|artist_unicode| {
-                    BasicSongInfo::new(title_unicode.to_string(), artist_unicode)
-                })
+                artist_unicode_opt
+                    .map(|artist_unicode| BasicSongInfo::new(title_unicode, artist_unicode))
             });
-            let (info_ascii, info_unicode) = if let Some(info_unicode_) = info_unicode_opt {
-                (Some(info_unknown), info_unicode_)
+            if let Some(info_unicode_) = info_unicode_opt {
+                (info_unknown, info_unicode_)
             } else {
-                (None, info_unknown)
-            };
-            let mut stmt = self
-                .connection
-                .prepare(PRP_STMT_OSU_LAZER_LIST_BEATMAPS_FROM_SET)
-                .unwrap();
-            let beatmap_from_set_db_listing_item: Vec<Osu50BeatmapDbListingItem> = stmt
-                .query_map(rusqlite::params![beatmapset_id], |row| {
+                (info_unknown.transliterate(), info_unknown)
+            }
+        };
+        let beatmap_from_set_db_listing_item: Vec<Osu50BeatmapDbListingItem> = match self
+            .connection
+            .prepare(PRP_STMT_OSU_LAZER_LIST_BEATMAPS_FROM_SET)
+            .and_then(|mut stmt| {
+                stmt.query_map(rusqlite::params![beatmapset_key], |row| {
+                    let non_empty = |ndx: usize| -> rusqlite::Result<Option<String>> {
+                        Ok(row
+                            .get::<_, Option<String>>(ndx)?
+                            .filter(|value| !value.is_empty()))
+                    };
+                    let details = BeatmapDetails {
+                        creator: non_empty(15)?,
+                        source: non_empty(16)?,
+                        tags: non_empty(17)?,
+                        version: non_empty(11)?,
+                        beatmap_id: row.get::<_, Option<i64>>(1)?.filter(|id| *id > 0),
+                        bpm: row.get::<_, Option<f64>>(12)?.filter(|bpm| *bpm > 0.0),
+                        length: row
+                            .get::<_, Option<f64>>(13)?
+                            .filter(|length| *length > 0.0)
+                            .map(|length| length / 1000.0),
+                        mode: row
+                            .get::<_, Option<i64>>(14)?
+                            .and_then(|ruleset_id| u8::try_from(ruleset_id).ok())
+                            .and_then(|ruleset_id| OsuDbGameMode::try_from(ruleset_id).ok())
+                            .map(|mode| mode.name()),
+                        ..BeatmapDetails::default()
+                    };
                     Ok(Osu50BeatmapDbListingItem::new(
                         row.get(0)?,
                         row.get(1)?,
                         row.get(2)?,
                         row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                        row.get(6)?,
+                        row.get(7)?,
+                        row.get(8)?,
+                        (row.get(9)?, row.get(10)?),
+                        details,
                     ))
                 })
-                .unwrap()
-                .filter_map(|x| x.ok())
-                .collect();
-            beatmap_from_set_db_listing_item
-                .into_iter()
-                .filter_map(|osu_betmap_db_listing| {
-                    let cloned_info_ascii = info_ascii.clone();
-                    let cloned_info_unicode = info_unicode.clone();
-                    let cloned_background = background.clone();
-                    let cloned_audio = audio.clone();
-                    let cloned_background_0 = self.beatmapset_db_info.background.0.clone();
-                    let cloned_audio_0 = self.beatmapset_db_info.audio.0.clone();
+                .map(|rows| rows.filter_map(|x| x.ok()).collect())
+            }) {
+            Ok(items) => items,
+            Err(x) => return vec![Err(ExportError::database(&self.database_path, x))],
+        };
+        let extension_of = |filename: &Option<String>| {
+            filename.as_ref().and_then(|filename| {
+                PathBuf::from(filename)
+                    .extension()
+                    .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
+            })
+        };
+        beatmap_from_set_db_listing_item
+            .into_iter()
+            .filter_map(|osu_betmap_db_listing| {
+                // Difficulties without metadata of their own share the set's.
+                let (audio_name, audio_hash) = if osu_betmap_db_listing.audio.1.is_some() {
+                    osu_betmap_db_listing.audio.clone()
+                } else {
+                    self.beatmapset_db_info.audio.clone()
+                };
+                let audio_hash = audio_hash?;
+                let (info_ascii, info_unicode) = match (
+                    osu_betmap_db_listing.title.clone(),
+                    osu_betmap_db_listing.artist.clone(),
+                ) {
+                    (Some(title), Some(artist)) => song_infos(
+                        title,
+                        artist,
+                        osu_betmap_db_listing.title_unicode.clone(),
+                        osu_betmap_db_listing.artist_unicode.clone(),
+                    ),
+                    _ => song_infos(
+                        self.beatmapset_db_info.title.clone(),
+                        self.beatmapset_db_info.artist.clone(),
+                        self.beatmapset_db_info.title_unicode.clone(),
+                        self.beatmapset_db_info.artist_unicode.clone(),
+                    ),
+                };
+                Some(
                     self.hash_resolver
-                        .resolve(&osu_betmap_db_listing.hash)
-                        .map(|beatmap_pathbuf| {
+                        .resolve(&audio_hash)
+                        .and_then(|audio| {
+                            Ok((
+                                audio,
+                                self.hash_resolver.resolve(&osu_betmap_db_listing.hash)?,
+                            ))
+                        })
+                        .map(|(audio, beatmap_pathbuf)| {
                             OsuBeatmapInfoHolder::new(
-                                cloned_info_ascii
-                                    .unwrap_or_else(|| cloned_info_unicode.filter_ascii()),
-                                cloned_info_unicode,
-                                beatmapset_id as u64,
-                                cloned_background,
-                                cloned_audio,
+                                info_ascii,
+                                info_unicode,
+                                beatmapset_id.clone(),
+                                background.clone(),
+                                audio,
                                 beatmap_pathbuf,
+                                osu_betmap_db_listing.md5_hash.clone(),
                                 (
-                                    cloned_audio_0.clone().and_then(|aud| {
-                                        PathBuf::from(&aud)
-                                            .extension()
-                                            .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
-                                    }),
-                                    cloned_background_0.clone().and_then(|bkg| {
-                                        PathBuf::from(&bkg)
-                                            .extension()
-                                            .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
-                                    }),
+                                    extension_of(&audio_name),
+                                    extension_of(&self.beatmapset_db_info.background.0),
                                 ),
+                                osu_betmap_db_listing.details.clone(),
                             )
-                        })
-                        .ok()
-                })
-                .collect()
-        } else {
-            vec![]
+                        }),
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct Osu50RealmBeatmapSetsReader {
+    pub hash_resolver: Arc<Osu50HashResolver>,
+    pub beatmapset_listing_items: Vec<Osu50RealmBeatmapSetListingItem>,
+    pub realm_path: PathBuf,
+    /// Kept as read, so a broken collections table only fails the exports
+    /// that need collections.
+    pub collections: Result<Vec<OsuCollection>, String>,
+}
+
+impl TryFrom<&PathBuf> for Osu50RealmBeatmapSetsReader {
+    type Error = ExportError;
+    fn try_from(path: &PathBuf) -> Result<Self, ExportError> {
+        if !path.is_dir() {
+            return Err(ExportError::Input(format!("{:?} is not a directory", path)));
+        }
+        let path = &lazer_storage_folder(path);
+        let files_path = path.join("files");
+        if !files_path.is_dir() {
+            return Err(ExportError::Input(format!(
+                "{:?} directory was not found in your osu!lazer directory",
+                files_path
+            )));
         }
+        let realm_path = path.join("client.realm");
+        if !realm_path.is_file() {
+            return Err(ExportError::Input(format!(
+                "{:?} file was not found in your osu!lazer directory",
+                realm_path
+            )));
+        }
+        let realm_error = |e: String| ExportError::database(&realm_path, e);
+        let realm = RealmFile::try_from(&realm_path).map_err(realm_error)?;
+        let beatmapsets_table = realm
+            .table(REALM_TABLE_OSU_LAZER_BEATMAPSETS)
+            .map_err(realm_error)?;
+        let mut beatmapsets_columns = vec!["OnlineID", "Hash", "DeletePending", "Files"];
+        // Not every lazer version has these, and they are only nice to have.
+        beatmapsets_columns.extend(
+            ["Status", "DateAdded"]
+                .into_iter()
+                .filter(|column| beatmapsets_table.has_column(column)),
+        );
+        let beatmapsets = beatmapsets_table
+            .objects(&beatmapsets_columns)
+            .map_err(realm_error)?;
+        let beatmaps_table = realm
+            .table(REALM_TABLE_OSU_LAZER_BEATMAPS)
+            .map_err(realm_error)?;
+        let mut beatmaps_columns = vec!["BeatmapSet", "Metadata", "Hash", "MD5Hash"];
+        beatmaps_columns.extend(
+            ["DifficultyName", "OnlineID", "BPM", "Length", "Ruleset"]
+                .into_iter()
+                .filter(|column| beatmaps_table.has_column(column)),
+        );
+        let beatmaps = beatmaps_table
+            .objects(&beatmaps_columns)
+            .map_err(realm_error)?;
+        let metadatas_table = realm
+            .table(REALM_TABLE_OSU_LAZER_BEATMAP_METADATA)
+            .map_err(realm_error)?;
+        let mut metadatas_columns = vec![
+            "Title",
+            "TitleUnicode",
+            "Artist",
+            "ArtistUnicode",
+            "AudioFile",
+            "BackgroundFile",
+        ];
+        metadatas_columns.extend(
+            ["Author", "Source", "Tags"]
+                .into_iter()
+                .filter(|column| metadatas_table.has_column(column)),
+        );
+        let metadatas = metadatas_table
+            .objects_by_key(&metadatas_columns)
+            .map_err(realm_error)?;
+        let rulesets = realm
+            .table(REALM_TABLE_OSU_LAZER_RULESETS)
+            .and_then(|table| table.objects_by_key(&["OnlineID"]))
+            .unwrap_or_default();
+        let users = realm
+            .table(REALM_TABLE_OSU_LAZER_USERS)
+            .and_then(|table| table.objects_by_key(&["Username"]))
+            .unwrap_or_default();
+        let named_file_usages = realm
+            .table(REALM_TABLE_OSU_LAZER_NAMED_FILE_USAGES)
+            .map_err(realm_error)?
+            .objects_by_key(&["File", "Filename"])
+            .map_err(realm_error)?;
+        let files = realm
+            .table(REALM_TABLE_OSU_LAZER_FILES)
+            .map_err(realm_error)?
+            .objects_by_key(&["Hash"])
+            .map_err(realm_error)?;
+        let mut beatmaps_by_set: HashMap<u64, Vec<&RealmObject>> = HashMap::new();
+        for beatmap in beatmaps.iter() {
+            if let Some(beatmapset_key) = beatmap.get("BeatmapSet").as_link() {
+                beatmaps_by_set
+                    .entry(beatmapset_key)
+                    .or_default()
+                    .push(beatmap);
+            }
+        }
+        let beatmapset_listing_items = beatmapsets
+            .iter()
+            .filter(|beatmapset| !beatmapset.get("DeletePending").as_bool().unwrap_or(false))
+            .map(|beatmapset| {
+                let id = BeatmapSetId::online_or_local(
+                    beatmapset.get("OnlineID").as_int(),
+                    &beatmapset.get("Hash").as_string().unwrap_or_default(),
+                );
+                let file_hashes: HashMap<String, String> = beatmapset
+                    .get("Files")
+                    .as_link_list()
+                    .iter()
+                    .filter_map(|usage_key| named_file_usages.get(usage_key))
+                    .filter_map(|usage| {
+                        let filename = usage.get("Filename").as_string()?;
+                        let file = files.get(&usage.get("File").as_link()?)?;
+                        Some((filename, file.get("Hash").as_string()?))
+                    })
+                    .collect();
+                let beatmap_listing_items = beatmaps_by_set
+                    .get(&beatmapset.key)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|beatmap| {
+                        let metadata = metadatas.get(&beatmap.get("Metadata").as_link()?)?;
+                        let non_empty = |column: &str| {
+                            metadata
+                                .get(column)
+                                .as_string()
+                                .filter(|value| !value.is_empty())
+                        };
+                        let with_hash = |filename: Option<String>| {
+                            let hash = filename
+                                .as_ref()
+                                .and_then(|filename| file_hashes.get(filename).cloned());
+                            (filename, hash)
+                        };
+                        let details = BeatmapDetails {
+                            creator: metadata
+                                .get("Author")
+                                .as_link()
+                                .and_then(|user_key| users.get(&user_key))
+                                .and_then(|user| user.get("Username").as_string())
+                                .filter(|username| !username.is_empty()),
+                            source: non_empty("Source"),
+                            tags: non_empty("Tags"),
+                            version: beatmap
+                                .get("DifficultyName")
+                                .as_string()
+                                .filter(|name| !name.is_empty()),
+                            beatmap_id: beatmap.get("OnlineID").as_int().filter(|id| *id > 0),
+                            bpm: beatmap.get("BPM").as_double().filter(|bpm| *bpm > 0.0),
+                            length: beatmap
+                                .get("Length")
+                                .as_double()
+                                .filter(|length| *length > 0.0)
+                                .map(|length| length / 1000.0),
+                            mode: beatmap
+                                .get("Ruleset")
+                                .as_link()
+                                .and_then(|ruleset_key| rulesets.get(&ruleset_key))
+                                .and_then(|ruleset| ruleset.get("OnlineID").as_int())
+                                .and_then(|online_id| u8::try_from(online_id).ok())
+                                .and_then(|online_id| OsuDbGameMode::try_from(online_id).ok())
+                                .map(|mode| mode.name()),
+                            ..BeatmapDetails::default()
+                        };
+                        Some(Osu50RealmBeatmapListingItem::new(
+                            beatmap.get("Hash").as_string()?,
+                            beatmap.get("MD5Hash").as_string(),
+                            metadata.get("Title").as_string().unwrap_or_default(),
+                            metadata.get("Artist").as_string().unwrap_or_default(),
+                            non_empty("TitleUnicode"),
+                            non_empty("ArtistUnicode"),
+                            with_hash(non_empty("BackgroundFile")),
+                            with_hash(non_empty("AudioFile")),
+                            details,
+                        ))
+                    })
+                    .collect();
+                Osu50RealmBeatmapSetListingItem::new(
+                    id,
+                    beatmapset.get("Status").as_int(),
+                    beatmapset.get("DateAdded").as_timestamp(),
+                    beatmap_listing_items,
+                )
+            })
+            .collect();
+        let collections = realm
+            .table(REALM_TABLE_OSU_LAZER_COLLECTIONS)
+            .and_then(|table| table.objects(&["Name", "BeatmapMD5Hashes"]))
+            .map(|collections| {
+                collections
+                    .iter()
+                    .map(|collection| {
+                        OsuCollection::new(
+                            collection.get("Name").as_string().unwrap_or_default(),
+                            collection.get("BeatmapMD5Hashes").as_string_list(),
+                        )
+                    })
+                    .collect()
+            });
+        Ok(Self::new(
+            Arc::new(Osu50HashResolver::new(files_path)),
+            beatmapset_listing_items,
+            realm_path.clone(),
+            collections,
+        ))
+    }
+}
+
+impl OsuBeatmapSets for Osu50RealmBeatmapSetsReader {
+    fn boxed(self) -> Box<dyn OsuBeatmapSets> {
+        Box::new(self)
+    }
+    fn collections(&self) -> Result<Vec<OsuCollection>, ExportError> {
+        self.collections
+            .clone()
+            .map_err(|e| ExportError::database(&self.realm_path, e))
+    }
+    fn beatmap_sets(&self) -> Result<Vec<Box<dyn OsuBeatmapSet>>, ExportError> {
+        Ok(self
+            .beatmapset_listing_items
+            .iter()
+            .map(|beatmapset_listing_item| {
+                Osu50RealmBeatmapSet::new(
+                    self.hash_resolver.clone(),
+                    beatmapset_listing_item.clone(),
+                )
+                .boxed()
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct Osu50RealmBeatmapSet {
+    pub hash_resolver: Arc<Osu50HashResolver>,
+    pub beatmapset_listing_item: Osu50RealmBeatmapSetListingItem,
+}
+
+impl Osu50RealmBeatmapSet {
+    fn beatmap(
+        &self,
+        beatmap_listing_item: &Osu50RealmBeatmapListingItem,
+        audio_hash: &str,
+    ) -> Result<OsuBeatmapInfoHolder, ExportError> {
+        let audio = self.hash_resolver.resolve(audio_hash)?;
+        let background = beatmap_listing_item
+            .background
+            .1
+            .as_ref()
+            .and_then(|hash| self.hash_resolver.resolve(hash).ok());
+        let beatmap = self.hash_resolver.resolve(&beatmap_listing_item.hash)?;
+        let info_unknown = BasicSongInfo::new(
+            beatmap_listing_item.title.clone(),
+            beatmap_listing_item.artist.clone(),
+        );
+        let info_unicode_opt =
+            beatmap_listing_item
+                .title_unicode
+                .clone()
+                .and_then(|title_unicode| {
+                    beatmap_listing_item
+                        .artist_unicode
+                        .clone()
+                        .map(|artist_unicode| BasicSongInfo::new(title_unicode, artist_unicode))
+                });
+        let (info_ascii, info_unicode) = if let Some(info_unicode_) = info_unicode_opt {
+            (info_unknown, info_unicode_)
+        } else {
+            (info_unknown.transliterate(), info_unknown)
+        };
+        let extension_of = |filename: &Option<String>| {
+            filename.as_ref().and_then(|filename| {
+                PathBuf::from(filename)
+                    .extension()
+                    .and_then(|x| x.to_str().map(|y| y.to_lowercase()))
+            })
+        };
+        Ok(OsuBeatmapInfoHolder::new(
+            info_ascii,
+            info_unicode,
+            self.beatmapset_listing_item.id.clone(),
+            background,
+            audio,
+            beatmap.clone(),
+            beatmap_listing_item.md5_hash.clone(),
+            (
+                extension_of(&beatmap_listing_item.audio.0),
+                extension_of(&beatmap_listing_item.background.0),
+            ),
+            BeatmapDetails {
+                ranked_status: self
+                    .beatmapset_listing_item
+                    .status
+                    .and_then(lazer_ranked_status_name),
+                date_added: self.beatmapset_listing_item.date_added,
+                ..beatmap_listing_item.details.clone()
+            },
+        ))
+    }
+}
+
+impl OsuBeatmapSet for Osu50RealmBeatmapSet {
+    fn boxed(self) -> Box<dyn OsuBeatmapSet> {
+        Box::new(self)
+    }
+    fn beatmaps(&self) -> Vec<Result<OsuBeatmapInfoHolder, ExportError>> {
+        self.beatmapset_listing_item
+            .beatmaps
+            .iter()
+            .filter_map(|beatmap_listing_item| {
+                let audio_hash = beatmap_listing_item.audio.1.as_ref()?;
+                Some(self.beatmap(beatmap_listing_item, audio_hash))
+            })
+            .collect()
     }
 }
 
 impl OsuBeatmapInfoHolderSimple {
-    pub fn build_path(&self, path: &Path, filename_template: &str) -> PathBuf {
-        let mut filename: String = "".to_string();
-        let mut shift = false;
-        for ch in filename_template.chars() {
-            if shift {
-                shift = false;
-                match ch {
-                    'a' => filename.push_str(&self.info.artist),
-                    't' => filename.push_str(&self.info.title),
-                    'i' => filename.push_str(&format!("{}", self.beatmapset_id)),
-                    '/' => (),
-                    _ => filename.push(ch),
-                }
-            } else if ch == '%' {
-                shift = true;
-            } else if ch == '/' {
+    /// The value of a template field, see `template.rs`.
+    fn template_field(&self, name: &str, audio_extension: Option<&str>) -> Option<String> {
+        let (name, variant) = match name.rsplit_once('_') {
+            Some((base, variant)) if variant == "ascii" || variant == "unicode" => {
+                (base, Some(variant == "unicode"))
+            }
+            _ => (name, None),
+        };
+        let details = &self.details;
+        let text = |value: &Option<String>| -> Option<String> {
+            let value = value.clone()?;
+            if variant.unwrap_or_else(|| self.unicode_fields.contains(&name)) {
+                Some(value)
             } else {
-                filename.push(ch);
+                Some(transliterate(&value))
             }
+        };
+        let song_info = match variant {
+            Some(true) => &self.info_pair.unicode,
+            Some(false) => &self.info_pair.ascii,
+            None => &self.info,
+        };
+        match name {
+            "title" => Some(song_info.title.clone()),
+            "artist" => Some(song_info.artist.clone()),
+            "creator" => text(&details.creator),
+            "source" => text(&details.source),
+            "tags" => text(&details.tags),
+            "difficulty" => text(&details.version),
+            "set_id" => Some(self.beatmapset_id.to_string()),
+            "beatmap_id" => details.beatmap_id.map(|id| id.to_string()),
+            "bpm" => details.bpm.map(|bpm| format!("{:.0}", bpm)),
+            "length" => details.length.map(|length| format!("{:.0}", length)),
+            "mode" => details.mode.map(str::to_string),
+            "status" => details.ranked_status.map(str::to_string),
+            "date_added" => details.date_added.map(format_date),
+            "codec" => audio_extension.map(str::to_string),
+            _ => None,
         }
-        if shift {
-            filename.push('%');
-        }
-        for ch in &['<', '>', ':', '"', '/', '\\', '|', '?', '*', '\''] {
-            filename = filename.replace(*ch, "");
-        }
-        if let (Some(audio_extension), _) = &self.extensions {
-            filename.push('.');
-            filename.push_str(audio_extension);
+    }
+
+    /// Fills the template in, where each `/` starts a subfolder. Every path
+    /// component is sanitized on its own, so the fields can't add folders.
+    pub fn build_path(
+        &self,
+        path: &Path,
+        filename_template: &FilenameTemplate,
+        audio_extension: Option<&str>,
+        filesystem: FilesystemProfile,
+    ) -> PathBuf {
+        let mut components =
+            filename_template.render(&|name| self.template_field(name, audio_extension));
+        let filename =
+            filesystem.sanitize_file(&components.pop().unwrap_or_default(), audio_extension);
+        let mut destination = path.to_path_buf();
+        for folder in components.iter() {
+            let folder = filesystem.sanitize_folder(folder.trim());
+            if !folder.is_empty() {
+                destination.push(folder);
+            }
         }
-        path.join(filename)
+        destination.join(filename)
     }
 }
 
@@ -621,9 +1596,10 @@ pub enum FFProbeAudioStreamCodec {
     #[serde(rename = "mp3")]
     MP3,
     #[serde(rename = "vorbis")]
-    VORBIS,
+    Vorbis,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize, new)]
 pub struct FFProbeAudioStream {
     pub codec_name: FFProbeAudioStreamCodec,
@@ -652,6 +1628,28 @@ pub enum FFProbeStream {
 #[derive(Debug, Clone, Deserialize, new)]
 pub struct FFProbeFormat {}
 
+/// The little the transcoding policy needs from ffprobe, which unlike
+/// `FFProbeOutput` reads streams of any codec.
+#[derive(Debug, Clone, Deserialize, new)]
+pub struct FFProbeSummaryStream {
+    pub codec_type: String,
+    pub codec_name: Option<String>,
+    pub sample_rate: Option<String>,
+    pub bit_rate: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, new)]
+pub struct FFProbeSummaryFormat {
+    pub bit_rate: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, new)]
+pub struct FFProbeSummary {
+    pub streams: Vec<FFProbeSummaryStream>,
+    pub format: FFProbeSummaryFormat,
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize, new)]
 pub struct FFProbeOutput {
     pub streams: Vec<FFProbeStream>,
@@ -1,6 +1,11 @@
+use super::model::BeatmapDetails;
+use super::model::BeatmapSetId;
+
 pub const PRP_STMT_OSU_LAZER_LIST_BEATMAPSETS: &str = r#"
 SELECT DISTINCT
+    BeatmapSetInfo.ID,
     BeatmapSetInfo.OnlineBeatmapSetID,
+    BeatmapSetInfo.Hash,
     BeatmapMetadata.Title,
     BeatmapMetadata.Artist,
     BeatmapMetadata.TitleUnicode,
@@ -41,13 +46,13 @@ INNER JOIN
     BeatmapMetadata
 ON
     (BeatmapSetInfo.MetadataID = BeatmapMetadata.ID)
-WHERE
-    BeatmapSetInfo.OnlineBeatmapSetID IS NOT NULL
 "#;
 
 #[derive(Debug, Clone, new)]
 pub struct Osu50BeatmapSetDbListingItem {
     pub id: i64,
+    pub online_id: Option<i64>,
+    pub hash: Option<String>,
     pub title: String,
     pub artist: String,
     pub title_unicode: Option<String>,
@@ -58,24 +63,97 @@ pub struct Osu50BeatmapSetDbListingItem {
 
 pub const PRP_STMT_OSU_LAZER_LIST_BEATMAPS_FROM_SET: &str = r#"
 SELECT DISTINCT
-	BeatmapSetInfo.OnlineBeatmapSetID,
+	BeatmapSetInfo.ID,
 	BeatmapInfo.OnlineBeatmapID,
 	BeatmapInfo.Path,
-    BeatmapInfo.Hash
+    BeatmapInfo.Hash,
+    BeatmapInfo.MD5Hash,
+    BeatmapMetadata.Title,
+    BeatmapMetadata.Artist,
+    BeatmapMetadata.TitleUnicode,
+    BeatmapMetadata.ArtistUnicode,
+    BeatmapMetadata.AudioFile,
+    (
+    	SELECT DISTINCT
+			Hash
+		FROM
+			FileInfo
+		INNER JOIN
+			BeatmapSetFileInfo
+		ON
+			(FileInfo.ID = BeatmapSetFileInfo.FileInfoID)
+		WHERE
+			BeatmapSetFileInfo.BeatmapSetInfoID = BeatmapSetInfo.ID
+		AND
+		    BeatmapSetFileInfo.Filename = BeatmapMetadata.AudioFile
+	) AS AudioHash,
+    BeatmapInfo.Version,
+    BeatmapInfo.BPM,
+    BeatmapInfo.Length,
+    BeatmapInfo.RulesetID,
+    COALESCE(BeatmapMetadata.Author, BeatmapSetMetadata.Author),
+    COALESCE(BeatmapMetadata.Source, BeatmapSetMetadata.Source),
+    COALESCE(BeatmapMetadata.Tags, BeatmapSetMetadata.Tags)
 FROM
 	BeatmapInfo
 INNER JOIN
 	BeatmapSetInfo
 ON
     (BeatmapInfo.BeatmapSetInfoID = BeatmapSetInfo.ID)
+LEFT JOIN
+    BeatmapMetadata
+ON
+    (BeatmapInfo.MetadataID = BeatmapMetadata.ID)
+LEFT JOIN
+    BeatmapMetadata AS BeatmapSetMetadata
+ON
+    (BeatmapSetInfo.MetadataID = BeatmapSetMetadata.ID)
 WHERE
-    BeatmapSetInfo.OnlineBeatmapSetID = ?1
+    BeatmapSetInfo.ID = ?1
 "#;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, new)]
 pub struct Osu50BeatmapDbListingItem {
     pub set_id: i64,
-    pub id: i64,
+    pub id: Option<i64>,
     pub path: String,
     pub hash: String,
+    pub md5_hash: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub title_unicode: Option<String>,
+    pub artist_unicode: Option<String>,
+    pub audio: (Option<String>, Option<String>),
+    pub details: BeatmapDetails,
+}
+
+pub const REALM_TABLE_OSU_LAZER_BEATMAPSETS: &str = "class_BeatmapSet";
+pub const REALM_TABLE_OSU_LAZER_BEATMAPS: &str = "class_Beatmap";
+pub const REALM_TABLE_OSU_LAZER_BEATMAP_METADATA: &str = "class_BeatmapMetadata";
+pub const REALM_TABLE_OSU_LAZER_NAMED_FILE_USAGES: &str = "class_RealmNamedFileUsage";
+pub const REALM_TABLE_OSU_LAZER_FILES: &str = "class_File";
+pub const REALM_TABLE_OSU_LAZER_COLLECTIONS: &str = "class_BeatmapCollection";
+pub const REALM_TABLE_OSU_LAZER_RULESETS: &str = "class_Ruleset";
+pub const REALM_TABLE_OSU_LAZER_USERS: &str = "class_RealmUser";
+
+#[derive(Debug, Clone, new)]
+pub struct Osu50RealmBeatmapSetListingItem {
+    pub id: BeatmapSetId,
+    pub status: Option<i64>,
+    pub date_added: Option<i64>,
+    pub beatmaps: Vec<Osu50RealmBeatmapListingItem>,
+}
+
+#[derive(Debug, Clone, new)]
+pub struct Osu50RealmBeatmapListingItem {
+    pub hash: String,
+    pub md5_hash: Option<String>,
+    pub title: String,
+    pub artist: String,
+    pub title_unicode: Option<String>,
+    pub artist_unicode: Option<String>,
+    pub background: (Option<String>, Option<String>),
+    pub audio: (Option<String>, Option<String>),
+    pub details: BeatmapDetails,
 }
@@ -0,0 +1,599 @@
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+// Reader for osu!stable's binary `osu!.db` database, following the legacy
+// database file structure documented on the osu! wiki.
+
+const OSU_DB_VERSION_FLOAT_DIFFICULTY: i32 = 20140609;
+const OSU_DB_VERSION_NO_ENTRY_SIZE: i32 = 20191106;
+const OSU_DB_VERSION_FLOAT_STAR_RATING: i32 = 20250107;
+const DOTNET_TICKS_AT_UNIX_EPOCH: i64 = 621_355_968_000_000_000;
+const DOTNET_TICKS_PER_SECOND: i64 = 10_000_000;
+
+#[derive(Debug, Clone, new)]
+pub struct OsuDbCursor<'a> {
+    pub data: &'a [u8],
+    #[new(value = "0")]
+    pub position: usize,
+}
+
+impl OsuDbCursor<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| format!("Unexpected end of file at byte {}", self.position))?;
+        let slice = &self.data[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut buffer = [0u8; N];
+        buffer.copy_from_slice(self.take(N)?);
+        Ok(buffer)
+    }
+    pub fn skip(&mut self, count: usize) -> Result<(), String> {
+        self.take(count).map(|_| ())
+    }
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take_array::<1>()?[0])
+    }
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+    pub fn read_i16(&mut self) -> Result<i16, String> {
+        Ok(i16::from_le_bytes(self.take_array()?))
+    }
+    pub fn read_i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.take_array()?))
+    }
+    pub fn read_i64(&mut self) -> Result<i64, String> {
+        Ok(i64::from_le_bytes(self.take_array()?))
+    }
+    pub fn read_f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.take_array()?))
+    }
+    pub fn read_f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take_array()?))
+    }
+    pub fn read_uleb128(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift >= usize::BITS {
+                return Err(format!("Invalid ULEB128 at byte {}", self.position));
+            }
+            value |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+    pub fn read_string(&mut self) -> Result<String, String> {
+        match self.read_u8()? {
+            0x00 => Ok("".to_string()),
+            0x0b => {
+                let length = self.read_uleb128()?;
+                String::from_utf8(self.take(length)?.to_vec()).map_err(|e| format!("{:?}", e))
+            }
+            x => Err(format!(
+                "Invalid string marker {:#04x} at byte {}",
+                x,
+                self.position - 1
+            )),
+        }
+    }
+    pub fn read_datetime(&mut self) -> Result<Option<i64>, String> {
+        let ticks = self.read_i64()?;
+        Ok(if ticks <= 0 {
+            None
+        } else {
+            Some((ticks - DOTNET_TICKS_AT_UNIX_EPOCH) / DOTNET_TICKS_PER_SECOND)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsuDbRankedStatus {
+    Unknown,
+    Unsubmitted,
+    Pending,
+    Ranked,
+    Approved,
+    Qualified,
+    Loved,
+}
+
+impl OsuDbRankedStatus {
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Self::Unknown => None,
+            Self::Unsubmitted => Some("unsubmitted"),
+            Self::Pending => Some("pending"),
+            Self::Ranked => Some("ranked"),
+            Self::Approved => Some("approved"),
+            Self::Qualified => Some("qualified"),
+            Self::Loved => Some("loved"),
+        }
+    }
+}
+
+impl From<u8> for OsuDbRankedStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Unsubmitted,
+            2 => Self::Pending,
+            4 => Self::Ranked,
+            5 => Self::Approved,
+            6 => Self::Qualified,
+            7 => Self::Loved,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsuDbGameMode {
+    Osu,
+    Taiko,
+    Catch,
+    Mania,
+}
+
+impl OsuDbGameMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Osu => "osu",
+            Self::Taiko => "taiko",
+            Self::Catch => "catch",
+            Self::Mania => "mania",
+        }
+    }
+}
+
+impl TryFrom<u8> for OsuDbGameMode {
+    type Error = String;
+    fn try_from(value: u8) -> Result<Self, String> {
+        match value {
+            0 => Ok(Self::Osu),
+            1 => Ok(Self::Taiko),
+            2 => Ok(Self::Catch),
+            3 => Ok(Self::Mania),
+            x => Err(format!("Invalid game mode {}", x)),
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, new)]
+pub struct OsuDbBeatmap {
+    pub artist: String,
+    pub artist_unicode: String,
+    pub title: String,
+    pub title_unicode: String,
+    pub creator: String,
+    pub difficulty: String,
+    pub audio_file: String,
+    pub md5_hash: String,
+    pub osu_file: String,
+    pub ranked_status: OsuDbRankedStatus,
+    pub drain_time: i32,
+    pub total_time: i32,
+    pub bpm: Option<f64>,
+    pub beatmap_id: i32,
+    pub beatmapset_id: i32,
+    pub game_mode: OsuDbGameMode,
+    pub source: String,
+    pub tags: String,
+    pub last_played: Option<i64>,
+    pub folder_name: String,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, new)]
+pub struct OsuDb {
+    pub version: i32,
+    pub player_name: String,
+    pub beatmaps: Vec<OsuDbBeatmap>,
+}
+
+impl TryFrom<&PathBuf> for OsuDb {
+    type Error = String;
+    fn try_from(path: &PathBuf) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{:?}", e))?;
+        Self::try_from(&data[..]).map_err(|e| format!("{:?} is corrupted: {}", path, e))
+    }
+}
+
+impl TryFrom<&[u8]> for OsuDb {
+    type Error = String;
+    fn try_from(data: &[u8]) -> Result<Self, String> {
+        let mut cursor = OsuDbCursor::new(data);
+        let version = cursor.read_i32()?;
+        let _folder_count = cursor.read_i32()?;
+        let _account_unlocked = cursor.read_bool()?;
+        let _account_unlock_date = cursor.read_datetime()?;
+        let player_name = cursor.read_string()?;
+        let beatmap_count = cursor.read_i32()?;
+        let beatmaps = (0..beatmap_count.max(0))
+            .map(|_| read_osu_db_beatmap(&mut cursor, version))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self::new(version, player_name, beatmaps))
+    }
+}
+
+fn read_osu_db_beatmap(cursor: &mut OsuDbCursor, version: i32) -> Result<OsuDbBeatmap, String> {
+    let entry_end = if version < OSU_DB_VERSION_NO_ENTRY_SIZE {
+        let entry_size = cursor.read_i32()?;
+        Some(cursor.position + entry_size.max(0) as usize)
+    } else {
+        None
+    };
+    let artist = cursor.read_string()?;
+    let artist_unicode = cursor.read_string()?;
+    let title = cursor.read_string()?;
+    let title_unicode = cursor.read_string()?;
+    let creator = cursor.read_string()?;
+    let difficulty = cursor.read_string()?;
+    let audio_file = cursor.read_string()?;
+    let md5_hash = cursor.read_string()?;
+    let osu_file = cursor.read_string()?;
+    let ranked_status = OsuDbRankedStatus::from(cursor.read_u8()?);
+    let _hitobject_counts = (cursor.read_i16()?, cursor.read_i16()?, cursor.read_i16()?);
+    let _last_modification = cursor.read_datetime()?;
+    if version < OSU_DB_VERSION_FLOAT_DIFFICULTY {
+        cursor.skip(4)?;
+    } else {
+        cursor.skip(4 * 4)?;
+    }
+    let _slider_velocity = cursor.read_f64()?;
+    if version >= OSU_DB_VERSION_FLOAT_DIFFICULTY {
+        for _ in 0..4 {
+            let pair_count = cursor.read_i32()?;
+            for _ in 0..pair_count.max(0) {
+                cursor.skip(1)?;
+                let _mods = cursor.read_i32()?;
+                cursor.skip(1)?;
+                if version < OSU_DB_VERSION_FLOAT_STAR_RATING {
+                    let _star_rating = cursor.read_f64()?;
+                } else {
+                    let _star_rating = cursor.read_f32()?;
+                }
+            }
+        }
+    }
+    let drain_time = cursor.read_i32()?;
+    let total_time = cursor.read_i32()?;
+    let _preview_time = cursor.read_i32()?;
+    let timing_point_count = cursor.read_i32()?;
+    let mut bpm = None;
+    for _ in 0..timing_point_count.max(0) {
+        let beat_length = cursor.read_f64()?;
+        let _offset = cursor.read_f64()?;
+        let uninherited = cursor.read_bool()?;
+        if bpm.is_none() && uninherited && beat_length > 0.0 {
+            bpm = Some(60000.0 / beat_length);
+        }
+    }
+    let beatmap_id = cursor.read_i32()?;
+    let beatmapset_id = cursor.read_i32()?;
+    let _thread_id = cursor.read_i32()?;
+    let _grades = cursor.read_i32()?;
+    let _local_offset = cursor.read_i16()?;
+    let _stack_leniency = cursor.read_f32()?;
+    let game_mode = OsuDbGameMode::try_from(cursor.read_u8()?)?;
+    let source = cursor.read_string()?;
+    let tags = cursor.read_string()?;
+    let _online_offset = cursor.read_i16()?;
+    let _title_font = cursor.read_string()?;
+    let _unplayed = cursor.read_bool()?;
+    let last_played = cursor.read_datetime()?;
+    let _is_osz2 = cursor.read_bool()?;
+    let folder_name = cursor.read_string()?;
+    let _last_checked = cursor.read_datetime()?;
+    // ignore beatmap sound, ignore skin, disable storyboard, disable video, visual override
+    cursor.skip(5)?;
+    if version < OSU_DB_VERSION_FLOAT_DIFFICULTY {
+        let _unknown = cursor.read_i16()?;
+    }
+    let _last_modification_time = cursor.read_i32()?;
+    let _mania_scroll_speed = cursor.read_u8()?;
+    if let Some(end) = entry_end {
+        if end != cursor.position {
+            return Err(format!(
+                "Beatmap entry ended at byte {} instead of {}",
+                cursor.position, end
+            ));
+        }
+    }
+    Ok(OsuDbBeatmap::new(
+        artist,
+        artist_unicode,
+        title,
+        title_unicode,
+        creator,
+        difficulty,
+        audio_file,
+        md5_hash,
+        osu_file,
+        ranked_status,
+        drain_time,
+        total_time,
+        bpm,
+        beatmap_id,
+        beatmapset_id,
+        game_mode,
+        source,
+        tags,
+        last_played,
+        folder_name,
+    ))
+}
+
+#[derive(Debug, Clone, new)]
+pub struct OsuCollection {
+    pub name: String,
+    pub beatmap_hashes: Vec<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, new)]
+pub struct OsuCollectionDb {
+    pub version: i32,
+    pub collections: Vec<OsuCollection>,
+}
+
+impl TryFrom<&PathBuf> for OsuCollectionDb {
+    type Error = String;
+    fn try_from(path: &PathBuf) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{:?}", e))?;
+        Self::try_from(&data[..]).map_err(|e| format!("{:?} is corrupted: {}", path, e))
+    }
+}
+
+impl TryFrom<&[u8]> for OsuCollectionDb {
+    type Error = String;
+    fn try_from(data: &[u8]) -> Result<Self, String> {
+        let mut cursor = OsuDbCursor::new(data);
+        let version = cursor.read_i32()?;
+        let collection_count = cursor.read_i32()?;
+        let collections = (0..collection_count.max(0))
+            .map(|_| {
+                let name = cursor.read_string()?;
+                let beatmap_count = cursor.read_i32()?;
+                let beatmap_hashes = (0..beatmap_count.max(0))
+                    .map(|_| cursor.read_string())
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok(OsuCollection::new(name, beatmap_hashes))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self::new(version, collections))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the same little-endian types that `OsuDbCursor` reads.
+    #[derive(Default)]
+    struct OsuDbWriter(Vec<u8>);
+
+    impl OsuDbWriter {
+        fn u8(&mut self, value: u8) -> &mut Self {
+            self.0.push(value);
+            self
+        }
+        fn i16(&mut self, value: i16) -> &mut Self {
+            self.0.extend(value.to_le_bytes());
+            self
+        }
+        fn i32(&mut self, value: i32) -> &mut Self {
+            self.0.extend(value.to_le_bytes());
+            self
+        }
+        fn i64(&mut self, value: i64) -> &mut Self {
+            self.0.extend(value.to_le_bytes());
+            self
+        }
+        fn f32(&mut self, value: f32) -> &mut Self {
+            self.0.extend(value.to_le_bytes());
+            self
+        }
+        fn f64(&mut self, value: f64) -> &mut Self {
+            self.0.extend(value.to_le_bytes());
+            self
+        }
+        fn string(&mut self, value: &str) -> &mut Self {
+            if value.is_empty() {
+                return self.u8(0x00);
+            }
+            // Every string in these fixtures fits in a single ULEB128 byte.
+            assert!(value.len() < 0x80);
+            self.u8(0x0b).u8(value.len() as u8);
+            self.0.extend(value.as_bytes());
+            self
+        }
+    }
+
+    /// A beatmap entry laid out as osu! writes it in the given version.
+    fn beatmap_entry(version: i32) -> Vec<u8> {
+        let mut entry = OsuDbWriter::default();
+        entry
+            .string("Artist")
+            .string("アーティスト")
+            .string("Title")
+            .string("タイトル")
+            .string("Mapper")
+            .string("Insane")
+            .string("audio.mp3")
+            .string("0123456789abcdef0123456789abcdef")
+            .string("Artist - Title (Mapper) [Insane].osu")
+            .u8(4)
+            .i16(100)
+            .i16(20)
+            .i16(3)
+            .i64(0);
+        if version < OSU_DB_VERSION_FLOAT_DIFFICULTY {
+            entry.u8(4).u8(5).u8(6).u8(7);
+        } else {
+            entry.f32(4.0).f32(5.0).f32(6.5).f32(7.0);
+        }
+        entry.f64(1.4);
+        if version >= OSU_DB_VERSION_FLOAT_DIFFICULTY {
+            for mode in 0..4 {
+                entry.i32(if mode == 0 { 2 } else { 0 });
+                if mode == 0 {
+                    for mods in [0, 64] {
+                        entry.u8(0x08).i32(mods);
+                        if version < OSU_DB_VERSION_FLOAT_STAR_RATING {
+                            entry.u8(0x0d).f64(5.25);
+                        } else {
+                            entry.u8(0x0c).f32(5.25);
+                        }
+                    }
+                }
+            }
+        }
+        entry
+            .i32(90)
+            .i32(95_000)
+            .i32(30_000)
+            .i32(2)
+            .f64(-100.0)
+            .f64(0.0)
+            .u8(0)
+            .f64(500.0)
+            .f64(100.0)
+            .u8(1)
+            .i32(1234)
+            .i32(567)
+            .i32(0)
+            .i32(0x99999999u32 as i32)
+            .i16(0)
+            .f32(0.7)
+            .u8(3)
+            .string("Source")
+            .string("some tags")
+            .i16(0)
+            .string("")
+            .u8(0)
+            .i64(DOTNET_TICKS_AT_UNIX_EPOCH + 1_600_000_000 * DOTNET_TICKS_PER_SECOND)
+            .u8(0)
+            .string("567 Artist - Title")
+            .i64(0)
+            .u8(0)
+            .u8(0)
+            .u8(0)
+            .u8(0)
+            .u8(0);
+        if version < OSU_DB_VERSION_FLOAT_DIFFICULTY {
+            entry.i16(0);
+        }
+        entry.i32(0).u8(0);
+        entry.0
+    }
+
+    fn osu_db(version: i32) -> Vec<u8> {
+        let mut db = OsuDbWriter::default();
+        db.i32(version).i32(1).u8(1).i64(0).string("Player").i32(2);
+        for _ in 0..2 {
+            let entry = beatmap_entry(version);
+            if version < OSU_DB_VERSION_NO_ENTRY_SIZE {
+                db.i32(entry.len() as i32);
+            }
+            db.0.extend(entry);
+        }
+        db.i32(0);
+        db.0
+    }
+
+    fn assert_reads_entries(version: i32) {
+        let db = OsuDb::try_from(&osu_db(version)[..]).unwrap();
+        assert_eq!(db.version, version);
+        assert_eq!(db.player_name, "Player");
+        assert_eq!(db.beatmaps.len(), 2);
+        let beatmap = &db.beatmaps[1];
+        assert_eq!(beatmap.artist_unicode, "アーティスト");
+        assert_eq!(beatmap.difficulty, "Insane");
+        assert_eq!(beatmap.audio_file, "audio.mp3");
+        assert_eq!(beatmap.ranked_status, OsuDbRankedStatus::Ranked);
+        assert_eq!(beatmap.total_time, 95_000);
+        // The first timing point is inherited and doesn't set the tempo.
+        assert_eq!(beatmap.bpm, Some(120.0));
+        assert_eq!(beatmap.beatmap_id, 1234);
+        assert_eq!(beatmap.beatmapset_id, 567);
+        assert_eq!(beatmap.game_mode, OsuDbGameMode::Mania);
+        assert_eq!(beatmap.source, "Source");
+        assert_eq!(beatmap.tags, "some tags");
+        assert_eq!(beatmap.last_played, Some(1_600_000_000));
+        assert_eq!(beatmap.folder_name, "567 Artist - Title");
+    }
+
+    #[test]
+    fn reads_byte_difficulties() {
+        assert_reads_entries(20140401);
+    }
+
+    #[test]
+    fn reads_float_difficulties_with_entry_sizes() {
+        assert_reads_entries(OSU_DB_VERSION_FLOAT_DIFFICULTY);
+        assert_reads_entries(OSU_DB_VERSION_NO_ENTRY_SIZE - 1);
+    }
+
+    #[test]
+    fn reads_entries_without_sizes() {
+        assert_reads_entries(OSU_DB_VERSION_NO_ENTRY_SIZE);
+    }
+
+    #[test]
+    fn reads_float_star_ratings() {
+        assert_reads_entries(OSU_DB_VERSION_FLOAT_STAR_RATING - 1);
+        assert_reads_entries(OSU_DB_VERSION_FLOAT_STAR_RATING);
+    }
+
+    #[test]
+    fn rejects_entries_of_the_wrong_size() {
+        let mut data = osu_db(OSU_DB_VERSION_FLOAT_DIFFICULTY);
+        let entry_size_at = 4 + 4 + 1 + 8 + 8 + 4;
+        data[entry_size_at] += 1;
+        assert!(OsuDb::try_from(&data[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = osu_db(OSU_DB_VERSION_FLOAT_STAR_RATING);
+        for length in [0, 3, 30, data.len() / 2, data.len() - 5] {
+            assert!(OsuDb::try_from(&data[..length]).is_err(), "{}", length);
+        }
+    }
+
+    #[test]
+    fn reads_collections() {
+        let mut data = OsuDbWriter::default();
+        data.i32(20250107)
+            .i32(2)
+            .string("Favourites")
+            .i32(2)
+            .string("0123456789abcdef0123456789abcdef")
+            .string("fedcba9876543210fedcba9876543210")
+            .string("Empty")
+            .i32(0);
+        let collection_db = OsuCollectionDb::try_from(&data.0[..]).unwrap();
+        assert_eq!(collection_db.version, 20250107);
+        assert_eq!(collection_db.collections.len(), 2);
+        assert_eq!(collection_db.collections[0].name, "Favourites");
+        assert_eq!(
+            collection_db.collections[0].beatmap_hashes,
+            [
+                "0123456789abcdef0123456789abcdef",
+                "fedcba9876543210fedcba9876543210"
+            ]
+        );
+        assert!(collection_db.collections[1].beatmap_hashes.is_empty());
+        assert!(OsuCollectionDb::try_from(&data.0[..data.0.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use super::error::*;
+use super::model::OsuBeatmapInfoHolderSimple;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// What an export would do, as shown by `--dry-run` instead of doing it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for PlanFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown plan format {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackAction {
+    Copy,
+    Transcode,
+    Unchanged,
+}
+
+impl TrackAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Copy => "copy",
+            Self::Transcode => "transcode",
+            Self::Unchanged => "unchanged",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeReason {
+    /// The same set, found in another source.
+    Source,
+    /// The same song, found in another set.
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Serialize, new)]
+pub struct PlannedTrack {
+    pub action: TrackAction,
+    pub retag: bool,
+    /// Why the track is copied or transcoded.
+    pub reason: String,
+    pub beatmapset_id: String,
+    pub artist: String,
+    pub title: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+}
+
+impl PlannedTrack {
+    pub fn of(
+        destination: &Path,
+        beatmap_info: &OsuBeatmapInfoHolderSimple,
+        action: TrackAction,
+        retag: bool,
+        reason: &str,
+    ) -> Self {
+        Self::new(
+            action,
+            retag && action != TrackAction::Unchanged,
+            reason.to_string(),
+            beatmap_info.beatmapset_id.to_string(),
+            beatmap_info.info.artist.clone(),
+            beatmap_info.info.title.clone(),
+            beatmap_info.audio.clone(),
+            destination.to_path_buf(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, new)]
+pub struct MergedAudio {
+    pub audio: PathBuf,
+    pub kept: PathBuf,
+    pub reason: MergeReason,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExportPlan {
+    pub tracks: Vec<PlannedTrack>,
+    pub merged: Vec<MergedAudio>,
+    pub playlists: Vec<PathBuf>,
+    pub removals: Vec<PathBuf>,
+    pub collisions: Vec<ResolvedCollision>,
+    pub skipped: Vec<SkippedItem>,
+}
+
+impl ExportPlan {
+    pub fn print(&self, format: PlanFormat, songs_destination: &Path) -> Result<(), ExportError> {
+        match format {
+            PlanFormat::Json => {
+                let content = serde_json::to_string_pretty(self)
+                    .map_err(|e| ExportError::Input(e.to_string()))?;
+                println!("{}", content);
+            }
+            PlanFormat::Table => print!("{}", self.render_table(songs_destination)),
+        }
+        Ok(())
+    }
+
+    fn render_table(&self, songs_destination: &Path) -> String {
+        let relative = |path: &Path| -> String {
+            path.strip_prefix(songs_destination)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned()
+        };
+        let mut table = String::new();
+        let set_width = self
+            .tracks
+            .iter()
+            .map(|track| track.beatmapset_id.len())
+            .max()
+            .unwrap_or(0)
+            .max("SET".len());
+        let destination_width = self
+            .tracks
+            .iter()
+            .map(|track| relative(&track.destination).chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("DESTINATION".len());
+        table.push_str(&format!(
+            "{:<9}  {:<5}  {:<set_width$}  {:<destination_width$}  SOURCE\n",
+            "ACTION", "TAGS", "SET", "DESTINATION",
+        ));
+        for track in self.tracks.iter() {
+            table.push_str(&format!(
+                "{:<9}  {:<5}  {:<set_width$}  {:<destination_width$}  {}\n",
+                track.action.as_str(),
+                if track.retag { "yes" } else { "no" },
+                track.beatmapset_id,
+                relative(&track.destination),
+                track.source.to_string_lossy(),
+            ));
+        }
+        for merged in self.merged.iter() {
+            table.push_str(&format!(
+                "{:<9}  {:<5}  {}  ->  {}\n",
+                "merge",
+                match merged.reason {
+                    MergeReason::Source => "set",
+                    MergeReason::Duplicate => "song",
+                },
+                merged.audio.to_string_lossy(),
+                merged.kept.to_string_lossy(),
+            ));
+        }
+        for playlist in self.playlists.iter() {
+            table.push_str(&format!("{:<9}  {}\n", "playlist", relative(playlist)));
+        }
+        for removal in self.removals.iter() {
+            table.push_str(&format!("{:<9}  {}\n", "delete", relative(removal)));
+        }
+        for collision in self.collisions.iter() {
+            table.push_str(&format!(
+                "{:<9}  {}  ->  {}\n",
+                "rename",
+                relative(&collision.planned),
+                relative(&collision.destination),
+            ));
+        }
+        for skipped in self.skipped.iter() {
+            table.push_str(&format!(
+                "{:<9}  {}  {}\n",
+                "skip",
+                skipped
+                    .path
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                skipped.reason,
+            ));
+        }
+        let count = |action: TrackAction| {
+            self.tracks
+                .iter()
+                .filter(|track| track.action == action)
+                .count()
+        };
+        table.push_str(&format!(
+            "{} to copy, {} to transcode, {} unchanged, {} merged, {} renamed, {} playlist(s), {} to delete, {} skipped\n",
+            count(TrackAction::Copy),
+            count(TrackAction::Transcode),
+            count(TrackAction::Unchanged),
+            self.merged.len(),
+            self.collisions.len(),
+            self.playlists.len(),
+            self.removals.len(),
+            self.skipped.len(),
+        ));
+        table
+    }
+}
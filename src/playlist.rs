@@ -0,0 +1,59 @@
+use super::error::*;
+use super::model::*;
+use super::sanitize::*;
+use std::path::Path;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, new)]
+pub struct M3u8Playlist {
+    pub path: PathBuf,
+    pub collection_name: String,
+    pub entries: Vec<(PathBuf, OsuBeatmapInfoHolderSimple)>,
+}
+
+impl M3u8Playlist {
+    pub fn for_collection(
+        songs_destination: &Path,
+        collection_name: &str,
+        filesystem: FilesystemProfile,
+    ) -> Self {
+        Self::new(
+            songs_destination.join(filesystem.sanitize_file(collection_name, Some("m3u8"))),
+            collection_name.to_string(),
+            vec![],
+        )
+    }
+
+    pub fn push(&mut self, destination_path: &Path, beatmap_info: &OsuBeatmapInfoHolderSimple) {
+        if self
+            .entries
+            .iter()
+            .all(|(entry_path, _)| entry_path != destination_path)
+        {
+            self.entries
+                .push((destination_path.to_path_buf(), beatmap_info.clone()));
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let base = self.path.parent().unwrap_or(Path::new(""));
+        let mut playlist = "#EXTM3U\n".to_string();
+        for (entry_path, beatmap_info) in self.entries.iter() {
+            let relative_path = entry_path.strip_prefix(base).unwrap_or(entry_path);
+            let relative_path_string = relative_path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            playlist.push_str(&format!(
+                "#EXTINF:-1,{} - {}\n{}\n",
+                beatmap_info.tag_info.artist, beatmap_info.tag_info.title, relative_path_string
+            ));
+        }
+        playlist
+    }
+
+    pub fn write(&self) -> Result<(), ExportError> {
+        std::fs::write(&self.path, self.render()).map_err(ExportError::io(&self.path))
+    }
+}
@@ -0,0 +1,814 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+
+// Minimal, read-only reader for the Realm Core file format used by osu!lazer's
+// `client.realm`. Only the node layouts lazer relies on are understood.
+
+const REALM_MNEMONIC: &[u8; 4] = b"T-DB";
+const REALM_STREAMING_TOP_REF: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+const REALM_STREAMING_MAGIC_COOKIE: u64 = 0x3034_1252_37E5_26C8;
+const REALM_NODE_HEADER_SIZE: usize = 8;
+
+const GROUP_TOP_TABLE_NAMES: usize = 0;
+const GROUP_TOP_TABLES: usize = 1;
+const TABLE_TOP_SPEC: usize = 0;
+const TABLE_TOP_CLUSTER_TREE: usize = 2;
+const SPEC_NAMES: usize = 1;
+const SPEC_COLUMN_KEYS: usize = 5;
+const CLUSTER_INNER_SUB_TREE_DEPTH: usize = 1;
+const CLUSTER_INNER_FIRST_CHILD: usize = 2;
+const CLUSTER_NODE_SHIFT_FACTOR: u64 = 8;
+
+const COL_TYPE_INT: u64 = 0;
+const COL_TYPE_BOOL: u64 = 1;
+const COL_TYPE_STRING: u64 = 2;
+const COL_TYPE_TIMESTAMP: u64 = 8;
+const COL_TYPE_FLOAT: u64 = 9;
+const COL_TYPE_DOUBLE: u64 = 10;
+const COL_TYPE_LINK: u64 = 12;
+const COL_TYPE_LINK_LIST: u64 = 13;
+
+const COL_ATTR_NULLABLE: u64 = 16;
+const COL_ATTR_LIST: u64 = 32;
+const COL_ATTR_COLLECTION: u64 = 32 | 64 | 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RealmValue {
+    Null,
+    Int(i64),
+    Bool(bool),
+    String(String),
+    Float(f32),
+    Double(f64),
+    Timestamp(i64, i32),
+    Link(u64),
+    LinkList(Vec<u64>),
+    StringList(Vec<String>),
+    Unsupported,
+}
+
+impl RealmValue {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(x) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(x) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn as_string(&self) -> Option<String> {
+        match self {
+            Self::String(x) => Some(x.clone()),
+            _ => None,
+        }
+    }
+    pub fn as_double(&self) -> Option<f64> {
+        match self {
+            Self::Double(x) => Some(*x),
+            Self::Float(x) => Some(*x as f64),
+            _ => None,
+        }
+    }
+    /// Seconds since the Unix epoch.
+    pub fn as_timestamp(&self) -> Option<i64> {
+        match self {
+            Self::Timestamp(seconds, _) => Some(*seconds),
+            _ => None,
+        }
+    }
+    pub fn as_link(&self) -> Option<u64> {
+        match self {
+            Self::Link(x) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn as_link_list(&self) -> Vec<u64> {
+        match self {
+            Self::LinkList(x) => x.clone(),
+            _ => vec![],
+        }
+    }
+    pub fn as_string_list(&self) -> Vec<String> {
+        match self {
+            Self::StringList(x) => x.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct RealmObject {
+    pub key: u64,
+    pub values: HashMap<String, RealmValue>,
+}
+
+impl RealmObject {
+    pub fn get(&self, column: &str) -> &RealmValue {
+        self.values.get(column).unwrap_or(&RealmValue::Null)
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct RealmColumn {
+    pub name: String,
+    pub key: u64,
+}
+
+impl RealmColumn {
+    fn leaf_index(&self) -> usize {
+        (self.key & 0xFFFF) as usize
+    }
+    fn column_type(&self) -> u64 {
+        (self.key >> 16) & 0x3F
+    }
+    fn attributes(&self) -> u64 {
+        (self.key >> 22) & 0xFF
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RealmNode<'a> {
+    data: &'a [u8],
+    size: usize,
+    width: usize,
+    width_type: u8,
+    is_inner: bool,
+    has_refs: bool,
+    context_flag: bool,
+}
+
+impl<'a> RealmNode<'a> {
+    fn check_index(&self, ndx: usize) -> Result<(), String> {
+        if ndx < self.size {
+            Ok(())
+        } else {
+            Err(format!(
+                "Realm index {} is out of bounds of a node of {}",
+                ndx, self.size
+            ))
+        }
+    }
+    fn get(&self, ndx: usize) -> Result<i64, String> {
+        self.check_index(ndx)?;
+        Ok(match self.width {
+            0 => 0,
+            1 | 2 | 4 => {
+                let bit = ndx * self.width;
+                let [byte] = self.bytes::<1>(bit / 8)?;
+                ((byte >> (bit % 8)) & ((1u8 << self.width) - 1)) as i64
+            }
+            8 => i8::from_le_bytes(self.bytes(ndx)?) as i64,
+            16 => i16::from_le_bytes(self.bytes(ndx * 2)?) as i64,
+            32 => i32::from_le_bytes(self.bytes(ndx * 4)?) as i64,
+            _ => i64::from_le_bytes(self.bytes(ndx * 8)?),
+        })
+    }
+    fn get_unsigned(&self, ndx: usize) -> Result<u64, String> {
+        self.check_index(ndx)?;
+        Ok(match self.width {
+            0..=4 => self.get(ndx)? as u64,
+            8 => u8::from_le_bytes(self.bytes(ndx)?) as u64,
+            16 => u16::from_le_bytes(self.bytes(ndx * 2)?) as u64,
+            32 => u32::from_le_bytes(self.bytes(ndx * 4)?) as u64,
+            _ => u64::from_le_bytes(self.bytes(ndx * 8)?),
+        })
+    }
+    fn bytes<const N: usize>(&self, start: usize) -> Result<[u8; N], String> {
+        let mut buffer = [0u8; N];
+        let bytes = self
+            .data
+            .get(start..start + N)
+            .ok_or_else(|| format!("Realm node is too short for byte {}", start + N))?;
+        buffer.copy_from_slice(bytes);
+        Ok(buffer)
+    }
+    fn get_ref(&self, ndx: usize) -> Result<Option<u64>, String> {
+        let value = self.get(ndx)?;
+        if value != 0 && value & 1 == 0 {
+            Ok(Some(value as u64))
+        } else {
+            Ok(None)
+        }
+    }
+    fn get_tagged(&self, ndx: usize) -> Result<Option<u64>, String> {
+        let value = self.get(ndx)? as u64;
+        if value & 1 == 1 {
+            Ok(Some(value >> 1))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct RealmFile {
+    pub data: Vec<u8>,
+    pub top_ref: u64,
+    /// The file format version of the current snapshot, which tells which
+    /// node layouts may appear.
+    pub format_version: u8,
+}
+
+impl TryFrom<&PathBuf> for RealmFile {
+    type Error = String;
+    fn try_from(path: &PathBuf) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{:?}", e))?;
+        if data.len() < 24 || &data[16..20] != REALM_MNEMONIC {
+            return Err(format!("{:?} is not a Realm file", path));
+        }
+        let top_ref_slot = (data[23] & 1) as usize;
+        let format_version = data[20 + top_ref_slot];
+        let mut top_ref = u64::from_le_bytes(
+            data[top_ref_slot * 8..top_ref_slot * 8 + 8]
+                .try_into()
+                .unwrap(),
+        );
+        if top_ref == REALM_STREAMING_TOP_REF {
+            if data.len() < 40 {
+                return Err(format!("{:?} has no streaming footer", path));
+            }
+            let footer = &data[data.len() - 16..];
+            let magic_cookie = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+            if magic_cookie != REALM_STREAMING_MAGIC_COOKIE {
+                return Err(format!("{:?} has a corrupted streaming footer", path));
+            }
+            top_ref = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        }
+        Ok(Self::new(data, top_ref, format_version))
+    }
+}
+
+impl RealmFile {
+    fn node(&self, node_ref: u64) -> Result<RealmNode<'_>, String> {
+        let out_of_bounds = || format!("Realm node ref {} is out of bounds", node_ref);
+        let start = usize::try_from(node_ref).map_err(|_| out_of_bounds())?;
+        let header = start
+            .checked_add(REALM_NODE_HEADER_SIZE)
+            .and_then(|end| self.data.get(start..end))
+            .ok_or_else(out_of_bounds)?;
+        let flags = header[4];
+        let width_type = (flags & 0x18) >> 3;
+        let width = (1usize << (flags & 0x07)) >> 1;
+        let size = ((header[5] as usize) << 16) | ((header[6] as usize) << 8) | header[7] as usize;
+        let byte_size = match width_type {
+            0 => (size * width).div_ceil(8),
+            1 => size * width,
+            2 => size,
+            // Compressed arrays, written by newer Realm Core versions.
+            _ => {
+                return Err(format!(
+                    "unsupported Realm file format {}: node at {} is a compressed array",
+                    self.format_version, node_ref
+                ))
+            }
+        };
+        let data_start = start + REALM_NODE_HEADER_SIZE;
+        let data = data_start
+            .checked_add(byte_size)
+            .and_then(|end| self.data.get(data_start..end))
+            .ok_or_else(|| format!("Realm node at {} is truncated", node_ref))?;
+        Ok(RealmNode {
+            data,
+            size,
+            width,
+            width_type,
+            is_inner: flags & 0x80 != 0,
+            has_refs: flags & 0x40 != 0,
+            context_flag: flags & 0x20 != 0,
+        })
+    }
+
+    fn child_ref(&self, parent: &RealmNode, ndx: usize) -> Result<u64, String> {
+        if ndx >= parent.size {
+            return Err(format!("Realm child {} is out of bounds", ndx));
+        }
+        parent
+            .get_ref(ndx)?
+            .ok_or_else(|| format!("Realm child {} is not a ref", ndx))
+    }
+
+    fn child(&self, parent: &RealmNode, ndx: usize) -> Result<RealmNode<'_>, String> {
+        self.node(self.child_ref(parent, ndx)?)
+    }
+
+    /// Reads a node of a tree being walked, which a corrupted file could
+    /// otherwise lead back to over and over.
+    fn unvisited_node(
+        &self,
+        node_ref: u64,
+        visited: &mut HashSet<u64>,
+    ) -> Result<RealmNode<'_>, String> {
+        if !visited.insert(node_ref) {
+            return Err(format!("Realm node at {} is part of a cycle", node_ref));
+        }
+        self.node(node_ref)
+    }
+
+    fn short_strings(&self, node: &RealmNode) -> Result<Vec<Option<String>>, String> {
+        (0..node.size)
+            .map(|ndx| {
+                if node.width == 0 {
+                    return Ok(None);
+                }
+                let item = node
+                    .data
+                    .get(ndx * node.width..(ndx + 1) * node.width)
+                    .ok_or_else(|| "Realm short strings are truncated".to_string())?;
+                let padding = item[node.width - 1] as usize;
+                if padding >= node.width {
+                    return Ok(None);
+                }
+                let length = node.width - 1 - padding;
+                String::from_utf8(item[..length].to_vec())
+                    .map(Some)
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .collect()
+    }
+
+    fn blob(&self, node: &RealmNode, visited: &mut HashSet<u64>) -> Result<Vec<u8>, String> {
+        if node.has_refs {
+            let mut buffer = vec![];
+            for ndx in 0..node.size {
+                let child = self.unvisited_node(self.child_ref(node, ndx)?, visited)?;
+                buffer.append(&mut self.blob(&child, visited)?);
+            }
+            Ok(buffer)
+        } else {
+            Ok(node.data.to_vec())
+        }
+    }
+
+    fn strings(&self, node: &RealmNode) -> Result<Vec<Option<String>>, String> {
+        let to_string = |bytes: &[u8]| {
+            let without_terminator = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+            String::from_utf8(without_terminator.to_vec()).map_err(|e| format!("{:?}", e))
+        };
+        match (node.has_refs, node.context_flag) {
+            (false, _) if node.width_type == 1 => self.short_strings(node),
+            (false, _) => Err("Realm enumerated string columns are not supported".to_string()),
+            (true, false) => {
+                let offsets = self.child(node, 0)?;
+                let blob = self.blob(&self.child(node, 1)?, &mut HashSet::new())?;
+                let nulls = if node.size > 2 {
+                    Some(self.child(node, 2)?)
+                } else {
+                    None
+                };
+                (0..offsets.size)
+                    .map(|ndx| {
+                        if let Some(nulls) = &nulls {
+                            if nulls.get(ndx)? != 0 {
+                                return Ok(None);
+                            }
+                        }
+                        let begin = if ndx == 0 {
+                            0
+                        } else {
+                            offsets.get(ndx - 1)? as usize
+                        };
+                        let end = offsets.get(ndx)? as usize;
+                        let bytes = blob
+                            .get(begin..end)
+                            .ok_or_else(|| "Realm string offsets are corrupted".to_string())?;
+                        to_string(bytes).map(Some)
+                    })
+                    .collect()
+            }
+            (true, true) => (0..node.size)
+                .map(|ndx| match node.get_ref(ndx)? {
+                    None => Ok(None),
+                    Some(blob_ref) => {
+                        to_string(&self.blob(&self.node(blob_ref)?, &mut HashSet::new())?).map(Some)
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn bplustree_leaves<'a>(
+        &'a self,
+        node: RealmNode<'a>,
+        visited: &mut HashSet<u64>,
+        leaves: &mut Vec<RealmNode<'a>>,
+    ) -> Result<(), String> {
+        if node.is_inner {
+            for ndx in 1..node.size.saturating_sub(1) {
+                let child = self.unvisited_node(self.child_ref(&node, ndx)?, visited)?;
+                self.bplustree_leaves(child, visited, leaves)?;
+            }
+        } else {
+            leaves.push(node);
+        }
+        Ok(())
+    }
+
+    fn list_values<T>(
+        &self,
+        node: &RealmNode,
+        size: usize,
+        leaf_values: impl Fn(&RealmNode) -> Result<Vec<T>, String>,
+    ) -> Result<Vec<Vec<T>>, String> {
+        (0..size)
+            .map(|ndx| match node.get_ref(ndx)? {
+                None => Ok(vec![]),
+                Some(tree_ref) => {
+                    let mut leaves = vec![];
+                    self.bplustree_leaves(self.node(tree_ref)?, &mut HashSet::new(), &mut leaves)?;
+                    let mut values = vec![];
+                    for leaf in leaves.iter() {
+                        values.append(&mut leaf_values(leaf)?);
+                    }
+                    Ok(values)
+                }
+            })
+            .collect()
+    }
+
+    fn column_values(
+        &self,
+        column: &RealmColumn,
+        leaf: &RealmNode,
+        size: usize,
+    ) -> Result<Vec<RealmValue>, String> {
+        let attributes = column.attributes();
+        let nullable = attributes & COL_ATTR_NULLABLE != 0;
+        let is_list = attributes & COL_ATTR_LIST != 0;
+        let is_link_list = column.column_type() == COL_TYPE_LINK_LIST
+            || (column.column_type() == COL_TYPE_LINK && is_list);
+        let is_string_list = column.column_type() == COL_TYPE_STRING && is_list;
+        if !is_link_list && !is_string_list && attributes & COL_ATTR_COLLECTION != 0 {
+            return Ok(vec![RealmValue::Unsupported; size]);
+        }
+        let node = self.child(leaf, column.leaf_index() + 1)?;
+        // Nullable integers are stored after the value that stands for null.
+        let int_null = |ndx: usize| -> Result<Option<i64>, String> {
+            let value = node.get(ndx + 1)?;
+            if value == node.get(0)? {
+                Ok(None)
+            } else {
+                Ok(Some(value))
+            }
+        };
+        let values = match column.column_type() {
+            _ if is_link_list => self
+                .list_values(&node, size, |leaf| {
+                    (0..leaf.size)
+                        .map(|ndx| leaf.get(ndx).map(|key| key as u64))
+                        .collect()
+                })?
+                .into_iter()
+                .map(RealmValue::LinkList)
+                .collect(),
+            _ if is_string_list => self
+                .list_values(&node, size, |leaf| {
+                    Ok(self.strings(leaf)?.into_iter().flatten().collect())
+                })?
+                .into_iter()
+                .map(RealmValue::StringList)
+                .collect(),
+            COL_TYPE_INT if nullable => (0..size)
+                .map(|ndx| {
+                    Ok(int_null(ndx)?
+                        .map(RealmValue::Int)
+                        .unwrap_or(RealmValue::Null))
+                })
+                .collect::<Result<_, String>>()?,
+            COL_TYPE_INT => (0..size)
+                .map(|ndx| node.get(ndx).map(RealmValue::Int))
+                .collect::<Result<_, String>>()?,
+            COL_TYPE_BOOL if nullable => (0..size)
+                .map(|ndx| {
+                    Ok(int_null(ndx)?
+                        .map(|x| RealmValue::Bool(x != 0))
+                        .unwrap_or(RealmValue::Null))
+                })
+                .collect::<Result<_, String>>()?,
+            COL_TYPE_BOOL => (0..size)
+                .map(|ndx| node.get(ndx).map(|x| RealmValue::Bool(x != 0)))
+                .collect::<Result<_, String>>()?,
+            COL_TYPE_STRING => self
+                .strings(&node)?
+                .into_iter()
+                .map(|x| x.map(RealmValue::String).unwrap_or(RealmValue::Null))
+                .collect(),
+            COL_TYPE_FLOAT => (0..size)
+                .map(|ndx| {
+                    let x = f32::from_le_bytes(node.bytes(ndx * 4)?);
+                    Ok(if x.is_nan() {
+                        RealmValue::Null
+                    } else {
+                        RealmValue::Float(x)
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+            COL_TYPE_DOUBLE => (0..size)
+                .map(|ndx| {
+                    let x = f64::from_le_bytes(node.bytes(ndx * 8)?);
+                    Ok(if x.is_nan() {
+                        RealmValue::Null
+                    } else {
+                        RealmValue::Double(x)
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+            COL_TYPE_TIMESTAMP => {
+                let seconds = self.child(&node, 0)?;
+                let nanoseconds = self.child(&node, 1)?;
+                (0..size)
+                    .map(|ndx| {
+                        let value = seconds.get(ndx + 1)?;
+                        if value == seconds.get(0)? {
+                            Ok(RealmValue::Null)
+                        } else {
+                            Ok(RealmValue::Timestamp(value, nanoseconds.get(ndx)? as i32))
+                        }
+                    })
+                    .collect::<Result<_, String>>()?
+            }
+            COL_TYPE_LINK => (0..size)
+                .map(|ndx| {
+                    Ok(match node.get(ndx)? {
+                        0 => RealmValue::Null,
+                        x => RealmValue::Link((x as u64).wrapping_sub(1)),
+                    })
+                })
+                .collect::<Result<_, String>>()?,
+            _ => vec![RealmValue::Unsupported; size],
+        };
+        if values.len() != size {
+            return Err(format!(
+                "Realm column {:?} has {} values for {} objects",
+                column.name,
+                values.len(),
+                size
+            ));
+        }
+        Ok(values)
+    }
+
+    fn cluster_leaves<'a>(
+        &'a self,
+        node: RealmNode<'a>,
+        key_offset: u64,
+        visited: &mut HashSet<u64>,
+        leaves: &mut Vec<(RealmNode<'a>, u64)>,
+    ) -> Result<(), String> {
+        if !node.is_inner {
+            leaves.push((node, key_offset));
+            return Ok(());
+        }
+        let keys = match node.get_ref(0)? {
+            Some(keys_ref) => Some(self.node(keys_ref)?),
+            None => None,
+        };
+        let shift =
+            node.get_tagged(CLUSTER_INNER_SUB_TREE_DEPTH)?.unwrap_or(0) * CLUSTER_NODE_SHIFT_FACTOR;
+        let mut children: Vec<u64> = vec![];
+        for ndx in CLUSTER_INNER_FIRST_CHILD..node.size {
+            children.extend(node.get_ref(ndx)?);
+        }
+        for (ndx, child_ref) in children.into_iter().enumerate() {
+            let child_offset = match &keys {
+                Some(k) => k.get_unsigned(ndx)?,
+                None => (ndx as u64) << shift,
+            };
+            let child_key_offset = key_offset
+                .checked_add(child_offset)
+                .ok_or_else(|| format!("Realm cluster at {} has corrupted keys", child_ref))?;
+            let child = self.unvisited_node(child_ref, visited)?;
+            self.cluster_leaves(child, child_key_offset, visited, leaves)?;
+        }
+        Ok(())
+    }
+
+    pub fn table(&self, name: &str) -> Result<RealmTable<'_>, String> {
+        let top = self.node(self.top_ref)?;
+        let table_names = self.short_strings(&self.child(&top, GROUP_TOP_TABLE_NAMES)?)?;
+        let tables = self.child(&top, GROUP_TOP_TABLES)?;
+        let table_ndx = table_names
+            .iter()
+            .position(|table_name| table_name.as_deref() == Some(name))
+            .ok_or_else(|| format!("Realm table {:?} was not found", name))?;
+        let table_top = self.child(&tables, table_ndx)?;
+        let spec = self.child(&table_top, TABLE_TOP_SPEC)?;
+        let column_names = self.short_strings(&self.child(&spec, SPEC_NAMES)?)?;
+        let column_keys = self.child(&spec, SPEC_COLUMN_KEYS)?;
+        let columns = column_names
+            .into_iter()
+            .enumerate()
+            .map(|(ndx, column_name)| {
+                let key = column_keys.get(ndx)? as u64;
+                Ok(RealmColumn::new(column_name.unwrap_or_default(), key))
+            })
+            .collect::<Result<_, String>>()?;
+        let cluster_tree_ref = table_top
+            .get_ref(TABLE_TOP_CLUSTER_TREE)?
+            .ok_or_else(|| format!("Realm table {:?} has no cluster tree", name))?;
+        Ok(RealmTable::new(
+            self,
+            name.to_string(),
+            columns,
+            cluster_tree_ref,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct RealmTable<'a> {
+    pub file: &'a RealmFile,
+    pub name: String,
+    pub columns: Vec<RealmColumn>,
+    pub cluster_tree_ref: u64,
+}
+
+impl RealmTable<'_> {
+    pub fn has_column(&self, column_name: &str) -> bool {
+        self.columns.iter().any(|column| column.name == column_name)
+    }
+    pub fn objects(&self, column_names: &[&str]) -> Result<Vec<RealmObject>, String> {
+        let columns: Vec<&RealmColumn> = column_names
+            .iter()
+            .map(|column_name| {
+                self.columns
+                    .iter()
+                    .find(|column| &column.name == column_name)
+                    .ok_or_else(|| {
+                        format!(
+                            "Realm column {:?} was not found in table {:?}",
+                            column_name, self.name
+                        )
+                    })
+            })
+            .collect::<Result<_, String>>()?;
+        let mut leaves = vec![];
+        self.file.cluster_leaves(
+            self.file.node(self.cluster_tree_ref)?,
+            0,
+            &mut HashSet::new(),
+            &mut leaves,
+        )?;
+        let mut objects = vec![];
+        for (leaf, key_offset) in leaves {
+            let key = |offset: u64| {
+                key_offset
+                    .checked_add(offset)
+                    .ok_or_else(|| format!("Realm table {:?} has corrupted keys", self.name))
+            };
+            let keys: Vec<u64> = match leaf.get_ref(0)? {
+                Some(keys_ref) => {
+                    let keys_node = self.file.node(keys_ref)?;
+                    (0..keys_node.size)
+                        .map(|ndx| key(keys_node.get_unsigned(ndx)?))
+                        .collect::<Result<_, String>>()?
+                }
+                None => (0..leaf.get_tagged(0)?.unwrap_or(0))
+                    .map(key)
+                    .collect::<Result<_, String>>()?,
+            };
+            let mut columns_values = vec![];
+            for column in columns.iter() {
+                columns_values.push(self.file.column_values(column, &leaf, keys.len())?);
+            }
+            for (ndx, key) in keys.into_iter().enumerate() {
+                let values = columns
+                    .iter()
+                    .zip(columns_values.iter())
+                    .map(|(column, column_values)| {
+                        (column.name.clone(), column_values[ndx].clone())
+                    })
+                    .collect();
+                objects.push(RealmObject::new(key, values));
+            }
+        }
+        Ok(objects)
+    }
+
+    pub fn objects_by_key(
+        &self,
+        column_names: &[&str],
+    ) -> Result<HashMap<u64, RealmObject>, String> {
+        Ok(self
+            .objects(column_names)?
+            .into_iter()
+            .map(|object| (object.key, object))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE_REF: u64 = 24;
+
+    /// A file holding a single node right after the file header.
+    fn file_with_node(flags: u8, size: usize, payload: &[u8]) -> RealmFile {
+        let mut data = vec![0u8; NODE_REF as usize];
+        data.extend_from_slice(b"AAAA");
+        data.push(flags);
+        data.extend_from_slice(&(size as u32).to_be_bytes()[1..]);
+        data.extend_from_slice(payload);
+        RealmFile::new(data, NODE_REF, 24)
+    }
+
+    #[test]
+    fn decodes_node_header() {
+        let file = file_with_node(0x80 | 0x40 | 0x20 | 0x04, 3, &[1, 2, 3]);
+        let node = file.node(NODE_REF).unwrap();
+        assert_eq!(node.size, 3);
+        assert_eq!(node.width, 8);
+        assert_eq!(node.width_type, 0);
+        assert!(node.is_inner);
+        assert!(node.has_refs);
+        assert!(node.context_flag);
+    }
+
+    #[test]
+    fn reads_packed_values() {
+        // Two bits per value, least significant bits first.
+        let file = file_with_node(0x02, 4, &[0b1110_0100]);
+        let node = file.node(NODE_REF).unwrap();
+        assert_eq!(node.width, 2);
+        let values: Vec<i64> = (0..4).map(|ndx| node.get(ndx).unwrap()).collect();
+        assert_eq!(values, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn reads_signed_and_unsigned_values() {
+        let file = file_with_node(0x05, 2, &[0xFE, 0xFF, 0x02, 0x00]);
+        let node = file.node(NODE_REF).unwrap();
+        assert_eq!(node.width, 16);
+        assert_eq!(node.get(0), Ok(-2));
+        assert_eq!(node.get_unsigned(0), Ok(0xFFFE));
+        assert_eq!(node.get_ref(1), Ok(Some(2)));
+        assert_eq!(node.get_tagged(1), Ok(None));
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_reads() {
+        let file = file_with_node(0x04, 2, &[1, 2]);
+        let node = file.node(NODE_REF).unwrap();
+        assert!(node.get(2).is_err());
+        assert!(node.get_unsigned(2).is_err());
+        assert!(file.node(1000).is_err());
+    }
+
+    #[test]
+    fn rejects_refs_past_the_address_space() {
+        let file = file_with_node(0x04, 2, &[1, 2]);
+        for node_ref in [u64::MAX, u64::MAX - 3, usize::MAX as u64 - 7] {
+            assert!(file.node(node_ref).is_err(), "{}", node_ref);
+        }
+    }
+
+    #[test]
+    fn rejects_cyclic_trees() {
+        // Inner nodes whose only child is themselves.
+        let file = file_with_node(0x80 | 0x40 | 0x04, 3, &[1, NODE_REF as u8, 1]);
+        let mut leaves = vec![];
+        let result = file.bplustree_leaves(
+            file.node(NODE_REF).unwrap(),
+            &mut HashSet::new(),
+            &mut leaves,
+        );
+        assert!(result.is_err());
+        // Cluster children only start after the keys and the depth.
+        let file = file_with_node(0x80 | 0x40 | 0x04, 3, &[1, 1, NODE_REF as u8]);
+        let mut leaves = vec![];
+        let result = file.cluster_leaves(
+            file.node(NODE_REF).unwrap(),
+            0,
+            &mut HashSet::new(),
+            &mut leaves,
+        );
+        assert!(result.is_err());
+
+        let file = file_with_node(0x40 | 0x04, 1, &[NODE_REF as u8]);
+        let result = file.blob(&file.node(NODE_REF).unwrap(), &mut HashSet::new());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_nodes() {
+        let file = file_with_node(0x04, 10, &[1, 2, 3]);
+        assert!(file.node(NODE_REF).is_err());
+    }
+
+    #[test]
+    fn reports_compressed_arrays_as_unsupported() {
+        let file = file_with_node(0x18 | 0x04, 1, &[1]);
+        let error = file.node(NODE_REF).unwrap_err();
+        assert!(
+            error.contains("unsupported Realm file format 24"),
+            "{}",
+            error
+        );
+    }
+}
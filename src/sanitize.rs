@@ -0,0 +1,219 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// Rules for the names the destination filesystem accepts, applied to every
+// folder and file name the export writes.
+
+const WINDOWS_FORBIDDEN: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*', '\''];
+const POSIX_FORBIDDEN: &[char] = &['/', '\0'];
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesystemProfile {
+    Posix,
+    /// What Windows accepts, which is as much as FAT32, exFAT and NTFS
+    /// allow there.
+    Windows,
+}
+
+impl FromStr for FilesystemProfile {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "posix" => Ok(Self::Posix),
+            "windows" | "ntfs" | "exfat" | "fat32" => Ok(Self::Windows),
+            _ => Err(format!("Unknown filesystem {:?}", s)),
+        }
+    }
+}
+
+impl FilesystemProfile {
+    fn is_windows(&self) -> bool {
+        *self == Self::Windows
+    }
+
+    /// Name lengths are counted in bytes on POSIX filesystems and in UTF-16
+    /// units on the others.
+    fn length(&self, name: &str) -> usize {
+        if self.is_windows() {
+            name.encode_utf16().count()
+        } else {
+            name.len()
+        }
+    }
+
+    fn truncate(&self, name: &str, max_length: usize) -> String {
+        let mut truncated = String::new();
+        for ch in name.chars() {
+            truncated.push(ch);
+            if self.length(&truncated) > max_length {
+                truncated.pop();
+                break;
+            }
+        }
+        truncated
+    }
+
+    fn clean(&self, name: &str) -> String {
+        let forbidden = if self.is_windows() {
+            WINDOWS_FORBIDDEN
+        } else {
+            POSIX_FORBIDDEN
+        };
+        let mut cleaned: String = name
+            .chars()
+            .filter(|ch| !forbidden.contains(ch))
+            .filter(|ch| !self.is_windows() || !ch.is_control())
+            .collect();
+        if self.is_windows() {
+            cleaned = cleaned.trim_end_matches(['.', ' ']).to_string();
+            let base = cleaned.split('.').next().unwrap_or_default().trim_end();
+            if WINDOWS_RESERVED.contains(&base.to_uppercase().as_str()) {
+                cleaned.insert(base.len(), '_');
+            }
+        }
+        cleaned
+    }
+
+    /// Repairs a folder name.
+    pub fn sanitize_folder(&self, name: &str) -> String {
+        let cleaned = self.clean(name);
+        let truncated = self.clean(&self.truncate(&cleaned, MAX_NAME_LENGTH));
+        match truncated.as_str() {
+            "." | ".." => String::new(),
+            _ => truncated,
+        }
+    }
+
+    /// Repairs a file name, shortening the stem so the extension is kept.
+    pub fn sanitize_file(&self, stem: &str, extension: Option<&str>) -> String {
+        let extension = extension
+            .map(|extension| format!(".{}", self.clean(extension)))
+            .unwrap_or_default();
+        let stem = self.clean(stem);
+        let room = MAX_NAME_LENGTH.saturating_sub(self.length(&extension));
+        let mut stem = self.clean(&self.truncate(&stem, room));
+        if stem.is_empty() || (extension.is_empty() && (stem == "." || stem == "..")) {
+            stem = "_".to_string();
+        }
+        stem + &extension
+    }
+
+    /// Adds a suffix to a file name, shortening its stem when needed.
+    pub fn with_stem_suffix(&self, path: &Path, suffix: &str) -> PathBuf {
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+        let room = MAX_NAME_LENGTH.saturating_sub(self.length(&extension) + self.length(suffix));
+        let stem = self.clean(&self.truncate(&stem, room));
+        path.with_file_name(format!("{}{}{}", stem, suffix, extension))
+    }
+
+    /// What two paths must differ in to be two files.
+    pub fn collision_key(&self, path: &Path) -> PathBuf {
+        if self.is_windows() {
+            PathBuf::from(path.to_string_lossy().to_lowercase())
+        } else {
+            path.to_path_buf()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOWS: FilesystemProfile = FilesystemProfile::Windows;
+    const POSIX: FilesystemProfile = FilesystemProfile::Posix;
+
+    #[test]
+    fn accepts_the_old_profile_names() {
+        for name in ["windows", "ntfs", "exfat", "fat32"] {
+            assert_eq!(FilesystemProfile::from_str(name), Ok(WINDOWS));
+        }
+        assert_eq!(FilesystemProfile::from_str("posix"), Ok(POSIX));
+        assert!(FilesystemProfile::from_str("hfs").is_err());
+    }
+
+    #[test]
+    fn cleans_forbidden_characters() {
+        assert_eq!(WINDOWS.clean("a<b>:c\"d|e?f*g'h\\i/j\u{7}"), "abcdefghij");
+        assert_eq!(POSIX.clean("a<b>:c?d/e"), "a<b>:c?de");
+    }
+
+    #[test]
+    fn cleans_trailing_dots_and_spaces_on_windows() {
+        assert_eq!(WINDOWS.clean("Title. . "), "Title");
+        assert_eq!(POSIX.clean("Title. "), "Title. ");
+    }
+
+    #[test]
+    fn cleans_reserved_names_on_windows() {
+        assert_eq!(WINDOWS.clean("CON"), "CON_");
+        assert_eq!(WINDOWS.clean("nul.mp3"), "nul_.mp3");
+        assert_eq!(WINDOWS.clean("Console"), "Console");
+        assert_eq!(POSIX.clean("CON"), "CON");
+    }
+
+    #[test]
+    fn truncates_by_the_units_of_the_filesystem() {
+        // U+1F3B5 takes two UTF-16 units and four bytes.
+        assert_eq!(WINDOWS.truncate("a\u{1F3B5}b", 2), "a");
+        assert_eq!(WINDOWS.truncate("a\u{1F3B5}b", 3), "a\u{1F3B5}");
+        assert_eq!(POSIX.truncate("ゆめ", 4), "ゆ");
+        assert_eq!(POSIX.truncate("ゆめ", 6), "ゆめ");
+    }
+
+    #[test]
+    fn keeps_the_extension_when_truncating() {
+        let file_name = WINDOWS.sanitize_file(&"a".repeat(300), Some("mp3"));
+        assert_eq!(file_name.len(), MAX_NAME_LENGTH);
+        assert!(file_name.ends_with("a.mp3"));
+        // What is left of the stem must not end in a dot either.
+        let stem = format!("{}. b", "a".repeat(250));
+        assert_eq!(
+            WINDOWS.sanitize_file(&stem, Some("mp3")),
+            format!("{}.mp3", "a".repeat(250))
+        );
+    }
+
+    #[test]
+    fn never_leaves_a_file_name_empty() {
+        assert_eq!(WINDOWS.sanitize_file("", Some("mp3")), "_.mp3");
+        assert_eq!(WINDOWS.sanitize_file("???", Some("mp3")), "_.mp3");
+        assert_eq!(POSIX.sanitize_file("..", None), "_");
+        assert_eq!(WINDOWS.sanitize_file("CON", Some("mp3")), "CON_.mp3");
+    }
+
+    #[test]
+    fn drops_folders_named_after_dots() {
+        assert_eq!(POSIX.sanitize_folder(".."), "");
+        assert_eq!(WINDOWS.sanitize_folder("Folder."), "Folder");
+    }
+
+    #[test]
+    fn suffixes_within_the_length_limit() {
+        let path = Path::new("out").join(format!("{}.mp3", "a".repeat(251)));
+        let suffixed = WINDOWS.with_stem_suffix(&path, " (2)");
+        let file_name = suffixed.file_name().unwrap().to_string_lossy();
+        assert_eq!(file_name.len(), MAX_NAME_LENGTH);
+        assert!(file_name.ends_with("a (2).mp3"));
+    }
+
+    #[test]
+    fn ignores_case_in_collisions_on_windows() {
+        let (upper, lower) = (Path::new("Out/Song.mp3"), Path::new("out/song.mp3"));
+        assert_eq!(WINDOWS.collision_key(upper), WINDOWS.collision_key(lower));
+        assert_ne!(POSIX.collision_key(upper), POSIX.collision_key(lower));
+    }
+}
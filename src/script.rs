@@ -0,0 +1,172 @@
+use super::template::TEXT_FIELDS;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Which of the Unicode or romanised metadata each text field is written in,
+// chosen separately for file names and for tags.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptChoice {
+    Unicode,
+    Romanised,
+    /// Unicode when the destination and its fonts can show it.
+    Auto,
+}
+
+impl FromStr for ScriptChoice {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "unicode" => Ok(Self::Unicode),
+            "romanised" | "romanized" | "ascii" => Ok(Self::Romanised),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!("Unknown script {:?}", s)),
+        }
+    }
+}
+
+/// The choice for every text field, written as a default and exceptions,
+/// e.g. `romanised,artist=unicode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldScripts {
+    default: ScriptChoice,
+    fields: HashMap<String, ScriptChoice>,
+}
+
+impl FieldScripts {
+    pub fn all(choice: ScriptChoice) -> Self {
+        Self {
+            default: choice,
+            fields: HashMap::new(),
+        }
+    }
+
+    pub fn choice(&self, field: &str) -> ScriptChoice {
+        self.fields.get(field).copied().unwrap_or(self.default)
+    }
+}
+
+impl FromStr for FieldScripts {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut field_scripts = Self::all(ScriptChoice::Auto);
+        for part in s.split(',').map(str::trim) {
+            match part.split_once('=') {
+                Some((field, choice)) => {
+                    let field = field.trim();
+                    if !TEXT_FIELDS.contains(&field) {
+                        return Err(format!("Unknown text field {:?}", field));
+                    }
+                    field_scripts
+                        .fields
+                        .insert(field.to_string(), choice.trim().parse()?);
+                }
+                None => field_scripts.default = part.parse()?,
+            }
+        }
+        Ok(field_scripts)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Kana,
+    Han,
+    Hangul,
+}
+
+impl FromStr for Script {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "latin" => Ok(Self::Latin),
+            "greek" => Ok(Self::Greek),
+            "cyrillic" => Ok(Self::Cyrillic),
+            "kana" => Ok(Self::Kana),
+            "han" => Ok(Self::Han),
+            "hangul" => Ok(Self::Hangul),
+            _ => Err(format!("Unknown script {:?}", s)),
+        }
+    }
+}
+
+/// Which scripts the fonts of the player can show, `None` for all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FontCoverage(Option<Vec<Script>>);
+
+impl FromStr for FontCoverage {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "all" {
+            return Ok(Self(None));
+        }
+        s.split(',')
+            .map(|script| script.trim().parse())
+            .collect::<Result<Vec<Script>, String>>()
+            .map(|scripts| Self(Some(scripts)))
+    }
+}
+
+impl FontCoverage {
+    pub fn all() -> Self {
+        Self(None)
+    }
+
+    fn covers_char(scripts: &[Script], ch: char) -> bool {
+        let needs = |script: Script| scripts.contains(&script);
+        match ch as u32 {
+            // Latin, with its punctuation and symbols.
+            0x0000..=0x024F | 0x1E00..=0x1EFF | 0x2000..=0x206F | 0x20A0..=0x20CF => {
+                ch.is_ascii() || needs(Script::Latin)
+            }
+            0x0370..=0x03FF | 0x1F00..=0x1FFF => needs(Script::Greek),
+            0x0400..=0x052F => needs(Script::Cyrillic),
+            0x3040..=0x30FF | 0x31F0..=0x31FF | 0xFF65..=0xFF9F => needs(Script::Kana),
+            0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF => needs(Script::Han),
+            0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => needs(Script::Hangul),
+            // CJK punctuation and full-width forms come with any CJK font.
+            0x3000..=0x303F | 0xFF00..=0xFF64 | 0xFFE0..=0xFFEF => {
+                needs(Script::Kana) || needs(Script::Han) || needs(Script::Hangul)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn covers(&self, text: &str) -> bool {
+        match &self.0 {
+            None => true,
+            Some(scripts) => text.chars().all(|ch| Self::covers_char(scripts, ch)),
+        }
+    }
+}
+
+/// Settles the choices of the command line for the values of one track.
+#[derive(Debug, Clone, new)]
+pub struct ScriptSelection {
+    filename: FieldScripts,
+    tags: FieldScripts,
+    fonts: FontCoverage,
+}
+
+impl ScriptSelection {
+    fn wants_unicode(&self, choice: ScriptChoice, value: &str) -> bool {
+        match choice {
+            ScriptChoice::Unicode => true,
+            ScriptChoice::Romanised => false,
+            ScriptChoice::Auto => self.fonts.covers(value),
+        }
+    }
+
+    /// Whether a field in file names should be written in Unicode.
+    pub fn filename_unicode(&self, field: &str, value: &str) -> bool {
+        self.wants_unicode(self.filename.choice(field), value)
+    }
+
+    /// Whether a field in tags should be written in Unicode.
+    pub fn tag_unicode(&self, field: &str, value: &str) -> bool {
+        self.wants_unicode(self.tags.choice(field), value)
+    }
+}
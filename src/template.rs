@@ -0,0 +1,385 @@
+use std::str::FromStr;
+
+// The language of `-t`. Text is copied as is, `/` starts a subfolder and
+// `{...}` is replaced by a field:
+//
+//   {title}                 a field
+//   {title_unicode}         the ASCII or Unicode variant of a text field
+//   {source|tags|"osu!"}    the first of these that isn't empty
+//   {title:lower:trunc(20)} modifiers, applied left to right
+//   [{difficulty} - ]       dropped unless every field inside has a value
+//
+// `\` escapes the next character. The old `%a`, `%t` and `%i` still work,
+// and templates without any `{...}` are read the old way, where `[`, `]` and
+// `\` are plain text.
+
+pub const TEXT_FIELDS: &[&str] = &["title", "artist", "creator", "source", "tags", "difficulty"];
+const OTHER_FIELDS: &[&str] = &[
+    "set_id",
+    "beatmap_id",
+    "bpm",
+    "length",
+    "mode",
+    "status",
+    "date_added",
+    "codec",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Modifier {
+    Lower,
+    Upper,
+    Capitalize,
+    Truncate(usize),
+    Pad(usize),
+}
+
+impl Modifier {
+    fn apply(&self, value: String) -> String {
+        match self {
+            Self::Lower => value.to_lowercase(),
+            Self::Upper => value.to_uppercase(),
+            Self::Capitalize => value
+                .split(' ')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+            Self::Truncate(length) => value.chars().take(*length).collect::<String>(),
+            Self::Pad(length) => {
+                let padding = length.saturating_sub(value.chars().count());
+                "0".repeat(padding) + &value
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    Field(String, Vec<Modifier>),
+    Literal(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Separator,
+    Placeholder(Vec<Term>),
+    Optional(Vec<Segment>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilenameTemplate {
+    segments: Vec<Segment>,
+}
+
+fn is_known_field(name: &str) -> bool {
+    let base = name
+        .strip_suffix("_ascii")
+        .or_else(|| name.strip_suffix("_unicode"));
+    match base {
+        Some(base) => TEXT_FIELDS.contains(&base),
+        None => TEXT_FIELDS.contains(&name) || OTHER_FIELDS.contains(&name),
+    }
+}
+
+fn parse_modifier(modifier: &str) -> Result<Modifier, String> {
+    let with_length = |name: &str| -> Result<Option<usize>, String> {
+        match modifier.strip_prefix(name) {
+            Some(rest) => rest
+                .strip_prefix('(')
+                .and_then(|rest| rest.strip_suffix(')'))
+                .and_then(|length| length.trim().parse().ok())
+                .map(Some)
+                .ok_or_else(|| format!("{:?} needs a length, like {}(10)", modifier, name)),
+            None => Ok(None),
+        }
+    };
+    match modifier {
+        "lower" => Ok(Modifier::Lower),
+        "upper" => Ok(Modifier::Upper),
+        "capitalize" => Ok(Modifier::Capitalize),
+        _ => {
+            if let Some(length) = with_length("trunc")? {
+                Ok(Modifier::Truncate(length))
+            } else if let Some(length) = with_length("pad")? {
+                Ok(Modifier::Pad(length))
+            } else {
+                Err(format!("unknown modifier {:?}", modifier))
+            }
+        }
+    }
+}
+
+fn parse_term(term: &str) -> Result<Term, String> {
+    let term = term.trim();
+    if let Some(literal) = term.strip_prefix('"') {
+        return literal
+            .strip_suffix('"')
+            .map(|literal| Term::Literal(literal.to_string()))
+            .ok_or_else(|| format!("unterminated text {:?}", term));
+    }
+    let mut parts = term.split(':');
+    let name = parts.next().unwrap_or_default().trim();
+    if !is_known_field(name) {
+        return Err(format!("unknown field {:?}", name));
+    }
+    let modifiers = parts
+        .map(|modifier| parse_modifier(modifier.trim()))
+        .collect::<Result<Vec<Modifier>, String>>()?;
+    Ok(Term::Field(name.to_string(), modifiers))
+}
+
+/// Splits the inside of `{...}` on the `|` that aren't quoted.
+fn parse_placeholder(placeholder: &str) -> Result<Segment, String> {
+    let mut terms: Vec<Term> = vec![];
+    let mut term = String::new();
+    let mut quoted = false;
+    for ch in placeholder.chars() {
+        match ch {
+            '"' => {
+                quoted = !quoted;
+                term.push(ch);
+            }
+            '|' if !quoted => terms.push(parse_term(&std::mem::take(&mut term))?),
+            _ => term.push(ch),
+        }
+    }
+    terms.push(parse_term(&term)?);
+    Ok(Segment::Placeholder(terms))
+}
+
+fn legacy_placeholder(ch: char) -> Segment {
+    let field = match ch {
+        'a' => "artist",
+        't' => "title",
+        'i' => "set_id",
+        '/' => return Segment::Text(String::new()),
+        _ => return Segment::Text(ch.to_string()),
+    };
+    Segment::Placeholder(vec![Term::Field(field.to_string(), vec![])])
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = String;
+    fn from_str(template: &str) -> Result<Self, String> {
+        let at =
+            |position: usize, reason: String| format!("at character {}: {}", position + 1, reason);
+        let is_legacy = !template.contains('{');
+        let mut groups: Vec<(usize, Vec<Segment>)> = vec![(0, vec![])];
+        let mut chars = template.chars().enumerate();
+        while let Some((position, ch)) = chars.next() {
+            let segment = match ch {
+                '\\' | '[' | ']' | '}' if is_legacy => Segment::Text(ch.to_string()),
+                '\\' => Segment::Text(chars.next().map(|(_, ch)| ch).unwrap_or('\\').to_string()),
+                '%' => match chars.next() {
+                    Some((_, ch)) => legacy_placeholder(ch),
+                    None => Segment::Text("%".to_string()),
+                },
+                '/' => Segment::Separator,
+                '{' => {
+                    let mut placeholder = String::new();
+                    let mut quoted = false;
+                    let mut closed = false;
+                    for (_, ch) in chars.by_ref() {
+                        if ch == '"' {
+                            quoted = !quoted;
+                        } else if ch == '}' && !quoted {
+                            closed = true;
+                            break;
+                        }
+                        placeholder.push(ch);
+                    }
+                    if !closed {
+                        return Err(at(position, "unclosed \"{\"".to_string()));
+                    }
+                    parse_placeholder(&placeholder).map_err(|e| at(position, e))?
+                }
+                '}' => return Err(at(position, "unopened \"}\"".to_string())),
+                '[' => {
+                    groups.push((position, vec![]));
+                    continue;
+                }
+                ']' => {
+                    if groups.len() == 1 {
+                        return Err(at(position, "unopened \"]\"".to_string()));
+                    }
+                    let (_, segments) = groups.pop().unwrap();
+                    Segment::Optional(segments)
+                }
+                _ => Segment::Text(ch.to_string()),
+            };
+            groups.last_mut().unwrap().1.push(segment);
+        }
+        if let Some((position, _)) = groups.get(1) {
+            return Err(at(*position, "unclosed \"[\"".to_string()));
+        }
+        let segments = groups.pop().unwrap().1;
+        // Every track would be named after its extension alone.
+        let file_name = segments
+            .rsplit(|segment| *segment == Segment::Separator)
+            .next()
+            .unwrap_or_default();
+        if file_name
+            .iter()
+            .all(|segment| matches!(segment, Segment::Text(text) if text.trim().is_empty()))
+        {
+            return Err("the file name after the last \"/\" is empty".to_string());
+        }
+        Ok(Self { segments })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Piece {
+    Text(String),
+    Separator,
+}
+
+/// Renders segments, telling whether every field in them had a value.
+fn render_segments(
+    segments: &[Segment],
+    field: &dyn Fn(&str) -> Option<String>,
+    pieces: &mut Vec<Piece>,
+) -> bool {
+    let mut complete = true;
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => pieces.push(Piece::Text(text.clone())),
+            Segment::Separator => pieces.push(Piece::Separator),
+            Segment::Placeholder(terms) => {
+                let value = terms.iter().find_map(|term| match term {
+                    Term::Literal(literal) => Some(literal.clone()),
+                    Term::Field(name, modifiers) => {
+                        field(name).filter(|value| !value.is_empty()).map(|value| {
+                            modifiers
+                                .iter()
+                                .fold(value, |value, modifier| modifier.apply(value))
+                        })
+                    }
+                });
+                match value {
+                    Some(value) => pieces.push(Piece::Text(value)),
+                    None => complete = false,
+                }
+            }
+            Segment::Optional(segments) => {
+                let mut optional_pieces = vec![];
+                if render_segments(segments, field, &mut optional_pieces) {
+                    pieces.extend(optional_pieces);
+                }
+            }
+        }
+    }
+    complete
+}
+
+impl FilenameTemplate {
+    /// Fills the template in, as path components still to be sanitized.
+    pub fn render(&self, field: &dyn Fn(&str) -> Option<String>) -> Vec<String> {
+        let mut pieces = vec![];
+        render_segments(&self.segments, field, &mut pieces);
+        let mut components = vec![String::new()];
+        for piece in pieces {
+            match piece {
+                Piece::Text(text) => components.last_mut().unwrap().push_str(&text),
+                Piece::Separator => components.push(String::new()),
+            }
+        }
+        components
+    }
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DD`.
+pub fn format_date(timestamp: i64) -> String {
+    // Howard Hinnant's days-to-civil algorithm.
+    let days = timestamp.div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> Vec<String> {
+        let template = FilenameTemplate::from_str(template).unwrap();
+        template.render(&|name| match name {
+            "artist" => Some("Camellia".to_string()),
+            "title" => Some("Exit This Earth's Atomosphere".to_string()),
+            "set_id" => Some("1234".to_string()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn renders_subfolders() {
+        assert_eq!(
+            render("%a/%i %t"),
+            vec!["Camellia", "1234 Exit This Earth's Atomosphere"]
+        );
+    }
+
+    #[test]
+    fn keeps_brackets_of_legacy_templates() {
+        assert_eq!(
+            render("osu! [%i] %a - %t"),
+            vec!["osu! [1234] Camellia - Exit This Earth's Atomosphere"]
+        );
+        assert_eq!(render("%a} \\ %%"), vec!["Camellia} \\ %"]);
+    }
+
+    #[test]
+    fn drops_optional_groups_with_missing_fields() {
+        assert_eq!(
+            render("{artist}[ ({source})] - {title}[ #{set_id}]"),
+            vec!["Camellia - Exit This Earth's Atomosphere #1234"]
+        );
+    }
+
+    #[test]
+    fn escapes_brackets_of_new_templates() {
+        assert_eq!(render("\\[{set_id}\\]"), vec!["[1234]"]);
+    }
+
+    #[test]
+    fn falls_back_and_applies_modifiers() {
+        assert_eq!(
+            render("{source|artist:upper:trunc(4)} {tags|\"none\"} {set_id:pad(6)}"),
+            vec!["CAME none 001234"]
+        );
+    }
+
+    #[test]
+    fn rejects_mistakes_in_new_templates() {
+        assert!(FilenameTemplate::from_str("{nope}").is_err());
+        assert!(FilenameTemplate::from_str("{title").is_err());
+        assert!(FilenameTemplate::from_str("{title}}").is_err());
+        assert!(FilenameTemplate::from_str("[{title}").is_err());
+        assert!(FilenameTemplate::from_str("{title:trunc}").is_err());
+    }
+
+    #[test]
+    fn rejects_an_empty_file_name() {
+        assert!(FilenameTemplate::from_str("%a/").is_err());
+        assert!(FilenameTemplate::from_str("%a/ ").is_err());
+        assert!(FilenameTemplate::from_str("").is_err());
+    }
+}
@@ -0,0 +1,398 @@
+use super::error::*;
+use base64::Engine;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+// Vorbis comments, the tags of Ogg Vorbis and Opus files, which audiotags
+// can't write. The comment header of each stream is replaced and every
+// other packet is copied over with the same page boundaries.
+
+const VORBIS_COMMENT_MAGIC: &[u8] = b"\x03vorbis";
+const OPUS_TAGS_MAGIC: &[u8] = b"OpusTags";
+/// Added to the name of the file being tagged, along with the process ID.
+const TEMPORARY_SUFFIX: &str = ".tagging-";
+
+/// Fields the exporter sets, so any earlier values are dropped.
+const REPLACED_FIELDS: &[&str] = &[
+    "TITLE",
+    "ARTIST",
+    "ALBUM",
+    "ALBUMARTIST",
+    "DISCNUMBER",
+    "DISCTOTAL",
+    "TOTALDISCS",
+    "TRACKNUMBER",
+    "TRACKTOTAL",
+    "TOTALTRACKS",
+    "DATE",
+    "YEAR",
+    "METADATA_BLOCK_PICTURE",
+    "COVERART",
+    "COVERARTMIME",
+];
+
+/// A front cover, as embedded by every tag format.
+#[derive(Debug, Clone, new)]
+pub struct CoverPicture {
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl CoverPicture {
+    /// The FLAC picture block that `METADATA_BLOCK_PICTURE` holds in base64.
+    fn flac_picture_block(&self) -> Vec<u8> {
+        let mut block = vec![];
+        block.extend(3u32.to_be_bytes()); // Front cover
+        block.extend((self.mime_type.len() as u32).to_be_bytes());
+        block.extend(self.mime_type.as_bytes());
+        block.extend(0u32.to_be_bytes()); // No description
+        block.extend(self.width.to_be_bytes());
+        block.extend(self.height.to_be_bytes());
+        block.extend(32u32.to_be_bytes()); // Colour depth
+        block.extend(0u32.to_be_bytes()); // Not indexed
+        block.extend((self.data.len() as u32).to_be_bytes());
+        block.extend(&self.data);
+        block
+    }
+}
+
+#[derive(Debug, Clone, new)]
+pub struct TagValues<'a> {
+    pub title: &'a str,
+    pub artist: &'a str,
+    pub album: &'a str,
+    pub cover: Option<&'a CoverPicture>,
+}
+
+impl TagValues<'_> {
+    fn comments(&self) -> Vec<(&'static str, String)> {
+        let mut comments = vec![
+            ("ALBUM", self.album.to_string()),
+            ("TITLE", self.title.to_string()),
+            ("ARTIST", self.artist.to_string()),
+        ];
+        if let Some(cover) = self.cover {
+            let block =
+                base64::engine::general_purpose::STANDARD.encode(cover.flac_picture_block());
+            comments.push(("METADATA_BLOCK_PICTURE", block));
+        }
+        comments
+    }
+}
+
+/// Reads the little-endian fields of a comment header.
+struct FieldReader<'a>(&'a [u8]);
+
+impl<'a> FieldReader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn take_length(&mut self) -> Option<usize> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+}
+
+struct CommentHeader {
+    magic: &'static [u8],
+    vendor: Vec<u8>,
+    comments: Vec<Vec<u8>>,
+}
+
+impl CommentHeader {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let magic = [VORBIS_COMMENT_MAGIC, OPUS_TAGS_MAGIC]
+            .into_iter()
+            .find(|magic| packet.starts_with(magic))?;
+        let mut reader = FieldReader(&packet[magic.len()..]);
+        let vendor_length = reader.take_length()?;
+        let vendor = reader.take(vendor_length)?.to_vec();
+        let count = reader.take_length()?;
+        let mut comments = vec![];
+        for _ in 0..count {
+            let length = reader.take_length()?;
+            comments.push(reader.take(length)?.to_vec());
+        }
+        Some(Self {
+            magic,
+            vendor,
+            comments,
+        })
+    }
+
+    fn field_name(comment: &[u8]) -> String {
+        let name = comment
+            .split(|byte| *byte == b'=')
+            .next()
+            .unwrap_or_default();
+        String::from_utf8_lossy(name).to_uppercase()
+    }
+
+    fn apply(&mut self, removed: &[&str], comments: &[(&str, String)]) {
+        self.comments
+            .retain(|comment| !removed.contains(&Self::field_name(comment).as_str()));
+        for (field, value) in comments.iter() {
+            self.comments
+                .push(format!("{}={}", field, value).into_bytes());
+        }
+    }
+
+    fn to_packet(&self) -> Vec<u8> {
+        let mut packet = self.magic.to_vec();
+        packet.extend((self.vendor.len() as u32).to_le_bytes());
+        packet.extend(&self.vendor);
+        packet.extend((self.comments.len() as u32).to_le_bytes());
+        for comment in self.comments.iter() {
+            packet.extend((comment.len() as u32).to_le_bytes());
+            packet.extend(comment);
+        }
+        // Vorbis ends its headers with a framing bit, Opus doesn't.
+        if self.magic == VORBIS_COMMENT_MAGIC {
+            packet.push(1);
+        }
+        packet
+    }
+}
+
+/// Where a file is rewritten before it replaces the original. Tracks that
+/// only differ by extension are tagged at the same time, and the name must
+/// not clash with them.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!("{}{}", TEMPORARY_SUFFIX, std::process::id()));
+    path.with_file_name(file_name)
+}
+
+/// The file whose rewrite an interrupted run left at `path`, if any.
+pub fn tagged_by_leftover(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let (tagged_name, process_id) = file_name.rsplit_once(TEMPORARY_SUFFIX)?;
+    if process_id.is_empty() || !process_id.chars().all(|ch| ch.is_ascii_digit()) {
+        return None;
+    }
+    Some(path.with_file_name(tagged_name))
+}
+
+/// Replaces the tags of an Ogg Vorbis or Opus file.
+pub fn write_tags(path: &Path, values: &TagValues) -> Result<(), ExportError> {
+    replace_comments(path, REPLACED_FIELDS, &values.comments())
+}
+
+/// Drops every comment of the `removed` fields, then adds `comments`.
+pub fn replace_comments(
+    path: &Path,
+    removed: &[&str],
+    comments: &[(&str, String)],
+) -> Result<(), ExportError> {
+    let file = std::fs::File::open(path).map_err(ExportError::io(path))?;
+    let mut reader = ogg::PacketReader::new(std::io::BufReader::new(file));
+    let mut packets = vec![];
+    while let Some(packet) = reader
+        .read_packet()
+        .map_err(|e| ExportError::tag(path, e))?
+    {
+        packets.push(packet);
+    }
+    let temporary_path = temporary_path(path);
+    let write_result = write_packets(&temporary_path, packets, removed, comments);
+    match write_result {
+        Ok(true) => std::fs::rename(&temporary_path, path).map_err(ExportError::io(path)),
+        Ok(false) => {
+            let _ = std::fs::remove_file(&temporary_path);
+            Err(ExportError::tag(path, "no Vorbis or Opus stream to tag"))
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&temporary_path);
+            Err(e)
+        }
+    }
+}
+
+/// Writes the packets out with new comment headers, telling whether there
+/// was any to replace.
+fn write_packets(
+    path: &Path,
+    packets: Vec<ogg::Packet>,
+    removed: &[&str],
+    comments: &[(&str, String)],
+) -> Result<bool, ExportError> {
+    let mut last_packets: HashMap<u32, usize> = HashMap::new();
+    for (index, packet) in packets.iter().enumerate() {
+        last_packets.insert(packet.stream_serial(), index);
+    }
+    let file = std::fs::File::create(path).map_err(ExportError::io(path))?;
+    let mut writer = ogg::PacketWriter::new(std::io::BufWriter::new(file));
+    let mut packet_numbers: HashMap<u32, usize> = HashMap::new();
+    let mut tagged = false;
+    for (index, packet) in packets.into_iter().enumerate() {
+        let serial = packet.stream_serial();
+        let absgp = packet.absgp_page();
+        let packet_number = packet_numbers.entry(serial).or_insert(0);
+        let mut end_info = if last_packets[&serial] == index {
+            ogg::PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            ogg::PacketWriteEndInfo::EndPage
+        } else {
+            ogg::PacketWriteEndInfo::NormalPacket
+        };
+        // The comment header is the second packet, and a new one might not
+        // end where the old one did.
+        let comment_header = match *packet_number {
+            1 => CommentHeader::parse(&packet.data),
+            _ => None,
+        };
+        let data = match comment_header {
+            Some(mut header) => {
+                header.apply(removed, comments);
+                tagged = true;
+                if header.magic == OPUS_TAGS_MAGIC {
+                    end_info = ogg::PacketWriteEndInfo::EndPage;
+                }
+                header.to_packet()
+            }
+            None => packet.data,
+        };
+        *packet_number += 1;
+        writer
+            .write_packet(data.into_boxed_slice(), serial, end_info, absgp)
+            .map_err(ExportError::io(path))?;
+    }
+    writer
+        .into_inner()
+        .into_inner()
+        .map_err(|e| ExportError::io(path)(e.into_error()))?;
+    Ok(tagged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment_packet(magic: &'static [u8], comments: &[&str]) -> Vec<u8> {
+        CommentHeader {
+            magic,
+            vendor: b"test".to_vec(),
+            comments: comments.iter().map(|c| c.as_bytes().to_vec()).collect(),
+        }
+        .to_packet()
+    }
+
+    /// Writes a stream of the given packets, each header on its own page.
+    fn write_stream(path: &Path, packets: &[Vec<u8>], headers: usize) {
+        let file = std::fs::File::create(path).unwrap();
+        let mut writer = ogg::PacketWriter::new(file);
+        for (index, packet) in packets.iter().enumerate() {
+            let end_info = if index + 1 == packets.len() {
+                ogg::PacketWriteEndInfo::EndStream
+            } else if index < headers {
+                ogg::PacketWriteEndInfo::EndPage
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+            let absgp = if index < headers {
+                0
+            } else {
+                index as u64 * 960
+            };
+            writer
+                .write_packet(packet.clone().into_boxed_slice(), 7, end_info, absgp)
+                .unwrap();
+        }
+    }
+
+    fn read_stream(path: &Path) -> Vec<Vec<u8>> {
+        let file = std::fs::File::open(path).unwrap();
+        let mut reader = ogg::PacketReader::new(file);
+        let mut packets = vec![];
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet.data);
+        }
+        packets
+    }
+
+    fn comments_of(packet: &[u8]) -> Vec<String> {
+        CommentHeader::parse(packet)
+            .unwrap()
+            .comments
+            .into_iter()
+            .map(|comment| String::from_utf8(comment).unwrap())
+            .collect()
+    }
+
+    fn round_trip(name: &str, magic: &'static [u8], mut packets: Vec<Vec<u8>>, headers: usize) {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        packets.insert(
+            1,
+            comment_packet(magic, &["title=Old", "COMMENT=kept", "Artist=Old"]),
+        );
+        write_stream(&path, &packets, headers);
+
+        let values = TagValues::new("Title", "Artist", "Album", None);
+        write_tags(&path, &values).unwrap();
+        let rewritten = read_stream(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rewritten.len(), packets.len());
+        assert_eq!(rewritten[0], packets[0]);
+        assert_eq!(rewritten[2..], packets[2..]);
+        assert_eq!(
+            comments_of(&rewritten[1]),
+            [
+                "COMMENT=kept",
+                "ALBUM=Album",
+                "TITLE=Title",
+                "ARTIST=Artist"
+            ]
+        );
+        assert!(!temporary_path(&path).exists());
+    }
+
+    #[test]
+    fn rewrites_vorbis_comments() {
+        let packets = vec![
+            b"\x01vorbis identification".to_vec(),
+            b"\x05vorbis setup".to_vec(),
+            vec![1; 300],
+            vec![2; 5000],
+            vec![3; 20],
+        ];
+        round_trip("round-trip.ogg", VORBIS_COMMENT_MAGIC, packets, 3);
+    }
+
+    #[test]
+    fn rewrites_opus_tags() {
+        let packets = vec![
+            b"OpusHead identification".to_vec(),
+            vec![1; 300],
+            vec![2; 5000],
+            vec![3; 20],
+        ];
+        round_trip("round-trip.opus", OPUS_TAGS_MAGIC, packets, 2);
+    }
+
+    #[test]
+    fn recognises_leftover_rewrites() {
+        let tagged = Path::new("/music/Set/Song.ogg");
+        assert_eq!(
+            tagged_by_leftover(&temporary_path(tagged)).as_deref(),
+            Some(tagged)
+        );
+        assert_eq!(tagged_by_leftover(tagged), None);
+        assert_eq!(
+            tagged_by_leftover(Path::new("/music/Set/Song.ogg.tagging-")),
+            None
+        );
+        assert_eq!(
+            tagged_by_leftover(Path::new("/music/Set/Song.ogg.tagging-old")),
+            None
+        );
+    }
+}